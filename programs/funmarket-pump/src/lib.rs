@@ -1,17 +1,42 @@
 // programs/funmarket-pump/src/lib.rs
 use anchor_lang::prelude::*;
 use anchor_lang::solana_program::{program::invoke, system_instruction};
+use solana_sha256_hasher::hashv;
+
+pub mod math_fpmm;
+pub mod math_lmsr;
 
 declare_id!("DADaDENa6gPZjy92BjctBDKGqNBHhqPokpr5uY2UY3uJ");
 
 /* ============================== CONSTANTS ============================== */
 
-pub const MAX_OUTCOMES: usize = 10;
+pub const MAX_OUTCOMES: usize = 64;
 pub const MAX_NAME_LEN: usize = 40;
 
+// UserPosition entries allocated up front (grows on demand)
+pub const POSITION_INITIAL_ENTRIES: usize = 2;
+
 // Fees (bps)
 pub const PLATFORM_FEE_BPS: u64 = 100; // 1%
-pub const CREATOR_FEE_BPS: u64 = 200;  // 2%
+pub const CREATOR_FEE_BPS: u64 = 200;  // 2%, suggested default
+pub const MIN_CREATOR_FEE_BPS: u16 = 0;
+pub const MAX_CREATOR_FEE_BPS: u16 = 500; // 5%
+pub const MAX_REFERRER_SHARE_BPS: u16 = 5_000; // of the platform fee
+
+// fee modes
+pub const FEE_MODE_TRADE: u8 = 0;      // platform + creator fee on every trade
+pub const FEE_MODE_SETTLEMENT: u8 = 1; // same bps taken from winnings at claim instead
+
+// dynamic fee: platform fee ramps toward resolution and rises with recent volatility
+pub const MAX_DYNAMIC_FEE_BPS: u16 = 1_000; // 10%
+pub const MAX_DYNAMIC_FEE_RAMP_SECONDS: i64 = 7 * 24 * 3600;
+pub const VOL_EMA_WEIGHT: u64 = 8;          // each trade's move counts 1/8
+pub const VOL_DECAY_SECONDS: i64 = 600;     // EMA halves after this long without trades
+pub const VOL_FEE_MULT_BPS: u64 = 1_000;    // fee += 10% of the EMA move (both in bps)
+
+// volume tiers (ProtocolConfig) and trader stats
+pub const MAX_FEE_TIERS: usize = 8;
+pub const VOLUME_WINDOW_DAYS: usize = 30;
 
 // Windows
 pub const CREATOR_PROPOSE_WINDOW: i64 = 24 * 3600; // 24h
@@ -19,15 +44,68 @@ pub const DISPUTE_WINDOW: i64 = 4 * 3600;          // 4h
 
 // Anti-manip limits
 pub const MAX_TRADE_SHARES_HARD: u64 = 5_000_000;
+pub const MAX_PARIMUTUEL_STAKE_LAMPORTS: u64 = 1_000_000_000_000; // 1000 SOL per trade
+
+// Market types
+pub const MARKET_TYPE_PARIMUTUEL: u8 = 2; // pool betting: 1 lamport staked = 1 share
+
+// batch_trade
+pub const MAX_BATCH_LEGS: usize = 16;
+
+// frequent batch auction
+pub const MAX_BATCH_SECONDS: i64 = 300;
+pub const MAX_QUEUED_ORDERS: usize = 16;
+pub const MAX_QUEUED_ORDERS_PER_TRADER: usize = 4;
+
+// limit orders
+pub const LIMIT_ORDER_TIP_LAMPORTS: u64 = 10_000; // paid to the keeper that fills
+
+// price history candles
+pub const PRICE_HISTORY_CANDLES: usize = 160; // keeps the account under the 10KB init limit
+pub const PRICE_HISTORY_MAX_OUTCOMES: usize = 8; // outcomes share the ring: >= 20 candles each
+pub const MIN_CANDLE_SECONDS: i64 = 60;
+pub const MAX_CANDLE_SECONDS: i64 = 24 * 3600;
+
+// stop-loss / take-profit triggers
+pub const TRIGGER_TIP_LAMPORTS: u64 = 10_000; // paid to the keeper that executes
 
-// Pricing (linear curve)
-pub const BASE_PRICE_LAMPORTS: u64 = 10_000_000; // 0.01 SOL
-pub const SLOPE_LAMPORTS_PER_SUPPLY: u64 = 1_000; // +0.000001 SOL per share supply
+// commit-reveal
+pub const COMMIT_REVEAL_TIMEOUT_SLOTS: u64 = 150; // ~1 min to reveal
+pub const COMMIT_PENALTY_BPS: u64 = 100; // 1% of deposit kept on unrevealed commits
+
+// Pricing (linear curve), per-market within these bounds
+pub const MIN_BASE_PRICE_LAMPORTS: u64 = 100_000; // 0.0001 SOL
+pub const MAX_BASE_PRICE_LAMPORTS: u64 = 10_000_000_000; // 10 SOL
+pub const MAX_SLOPE_LAMPORTS_PER_SUPPLY: u64 = 1_000_000; // +0.001 SOL per share supply
+
+// Graduation (linear -> lmsr)
+pub const GRADUATION_BACKING_BPS: u64 = 8_000; // share of collateral backing outstanding shares
+pub const MAX_GRADUATION_BONUS_BPS: u16 = 5_000; // of the platform fee, withheld until graduation
+
+// Pricing models
+pub const PRICING_LINEAR: u8 = 0;
+pub const PRICING_FPMM: u8 = 1;
+pub const PRICING_LMSR: u8 = 2;
+pub const PRICING_LS_LMSR: u8 = 3;
+
+// LS-LMSR: prices sum to at most 1 + vig; alpha = vig / (n ln n)
+pub const LS_LMSR_VIG_BPS: u16 = 500;
+
+// FPMM / LMSR liquidity providers
+pub const MAX_LP_FEE_BPS: u16 = 500; // 5%
+pub const LP_FEE_SCALE: u128 = 1_000_000_000_000; // fee-per-LP-share precision
 
 use anchor_lang::prelude::pubkey;
 pub const PLATFORM_WALLET: Pubkey =
     pubkey!("xBaRohQaEKaYm57K6yB6pGBVMPiD4jdJkykx5knU3xr");
 
+// Treasury: PLATFORM_WALLET is the default (sole) recipient until the split is configured
+pub const MAX_TREASURY_RECIPIENTS: usize = 8;
+
+// Insurance fund: share of each treasury distribution, taken before the split
+pub const DEFAULT_INSURANCE_BPS: u16 = 1_000; // 10%
+pub const MAX_INSURANCE_BPS: u16 = 5_000;
+
 // Admin key (ONLY used if disputes > 0)
 pub const ADMIN_AUTHORITY: Pubkey =
     pubkey!("2FuGyidfE3N1tAf6vWFFystFcEVRp4WydHTmFr71pA9Y");
@@ -44,20 +122,49 @@ pub mod funmarket_pump {
         ctx: Context<CreateMarket>,
         resolution_time: i64,
         outcome_names: Vec<String>,
-        market_type: u8, // 0=binary, 1=multi
+        market_type: u8, // 0=binary, 1=multi, 2=parimutuel
         b_lamports: u64, // kept for backwards compat (UI + stored), not used by linear pricing
 
         // anti-manip config
         max_position_bps: u16, // 500..9000, 10_000 disables
         max_trade_shares: u64, // 1..MAX_TRADE_SHARES_HARD
         cooldown_seconds: i64, // 0..120
+
+        // pricing
+        pricing_model: u8, // 0=linear, 1=fpmm, 2=lmsr, 3=ls-lmsr
+        lp_fee_bps: u16,   // fpmm/lmsr only, 0..MAX_LP_FEE_BPS
+        base_price_lamports: u64,       // MIN_BASE_PRICE_LAMPORTS..MAX_BASE_PRICE_LAMPORTS
+        slope_lamports_per_supply: u64, // 0..MAX_SLOPE_LAMPORTS_PER_SUPPLY (linear curve)
+
+        // graduation (linear only; 0 disables each threshold)
+        graduation_volume_lamports: u64,
+        graduation_supply: u64,
+        graduation_bonus_bps: u16, // creator bonus, share of platform fee, 0..MAX_GRADUATION_BONUS_BPS
+
+        // frequent batch auction: 0=continuous, else batch length 1..MAX_BATCH_SECONDS
+        batch_seconds: i64,
+
+        // buys must go through commit_trade / reveal_trade
+        commit_reveal: bool,
+
+        // creator's cut of every trade, MIN_CREATOR_FEE_BPS..=MAX_CREATOR_FEE_BPS
+        creator_fee_bps: u16,
+
+        // dynamic platform fee: 0 disables, else ceiling above PLATFORM_FEE_BPS..=MAX_DYNAMIC_FEE_BPS
+        dynamic_fee_max_bps: u16,
+        dynamic_fee_ramp_seconds: i64, // ramp length before resolution_time, 0..MAX_DYNAMIC_FEE_RAMP_SECONDS
+
+        fee_mode: u8, // FEE_MODE_TRADE or FEE_MODE_SETTLEMENT
     ) -> Result<()> {
         // outcomes
         require!(
             outcome_names.len() >= 2 && outcome_names.len() <= MAX_OUTCOMES,
             ErrorCode::InvalidOutcomes
         );
-        require!(market_type == 0 || market_type == 1, ErrorCode::InvalidOutcomes);
+        require!(
+            market_type == 0 || market_type == 1 || market_type == MARKET_TYPE_PARIMUTUEL,
+            ErrorCode::InvalidOutcomes
+        );
         if market_type == 0 {
             require!(outcome_names.len() == 2, ErrorCode::InvalidOutcomes);
         }
@@ -80,8 +187,14 @@ pub mod funmarket_pump {
             (max_position_bps >= 500 && max_position_bps <= 9000) || max_position_bps == 10_000,
             ErrorCode::InvalidAntiManip
         );
+        // parimutuel shares are lamports, so the per-trade cap is a stake cap
+        let max_trade_hard = if market_type == MARKET_TYPE_PARIMUTUEL {
+            MAX_PARIMUTUEL_STAKE_LAMPORTS
+        } else {
+            MAX_TRADE_SHARES_HARD
+        };
         require!(
-            max_trade_shares >= 1 && max_trade_shares <= MAX_TRADE_SHARES_HARD,
+            max_trade_shares >= 1 && max_trade_shares <= max_trade_hard,
             ErrorCode::InvalidAntiManip
         );
         require!(
@@ -89,17 +202,102 @@ pub mod funmarket_pump {
             ErrorCode::InvalidAntiManip
         );
 
+        // pricing config
+        require!(
+            pricing_model == PRICING_LINEAR
+                || pricing_model == PRICING_FPMM
+                || pricing_model == PRICING_LMSR
+                || pricing_model == PRICING_LS_LMSR,
+            ErrorCode::InvalidPricingModel
+        );
+        require!(lp_fee_bps <= MAX_LP_FEE_BPS, ErrorCode::InvalidPricingModel);
+        require!(
+            (MIN_CREATOR_FEE_BPS..=MAX_CREATOR_FEE_BPS).contains(&creator_fee_bps),
+            ErrorCode::InvalidFeeConfig
+        );
+        if dynamic_fee_max_bps > 0 {
+            require!(
+                dynamic_fee_max_bps as u64 > PLATFORM_FEE_BPS && dynamic_fee_max_bps <= MAX_DYNAMIC_FEE_BPS,
+                ErrorCode::InvalidFeeConfig
+            );
+        }
+        require!(
+            (0..=MAX_DYNAMIC_FEE_RAMP_SECONDS).contains(&dynamic_fee_ramp_seconds),
+            ErrorCode::InvalidFeeConfig
+        );
+        require!(
+            fee_mode == FEE_MODE_TRADE || fee_mode == FEE_MODE_SETTLEMENT,
+            ErrorCode::InvalidFeeConfig
+        );
+        // the dynamic fee only moves per-trade fees
+        if fee_mode == FEE_MODE_SETTLEMENT {
+            require!(dynamic_fee_max_bps == 0, ErrorCode::InvalidFeeConfig);
+        }
+        if pricing_model == PRICING_LINEAR {
+            require!(lp_fee_bps == 0, ErrorCode::InvalidPricingModel);
+        }
+        require!(
+            (MIN_BASE_PRICE_LAMPORTS..=MAX_BASE_PRICE_LAMPORTS).contains(&base_price_lamports),
+            ErrorCode::InvalidCurveParams
+        );
+        require!(
+            slope_lamports_per_supply <= MAX_SLOPE_LAMPORTS_PER_SUPPLY,
+            ErrorCode::InvalidCurveParams
+        );
+
+        // graduation config
+        require!(
+            graduation_bonus_bps <= MAX_GRADUATION_BONUS_BPS,
+            ErrorCode::InvalidGraduationConfig
+        );
+        if pricing_model != PRICING_LINEAR || market_type == MARKET_TYPE_PARIMUTUEL {
+            require!(
+                graduation_volume_lamports == 0 && graduation_supply == 0 && graduation_bonus_bps == 0,
+                ErrorCode::InvalidGraduationConfig
+            );
+        }
+
+        // parimutuel has no curve: stakes go straight into outcome pools
+        if market_type == MARKET_TYPE_PARIMUTUEL {
+            require!(pricing_model == PRICING_LINEAR, ErrorCode::InvalidPricingModel);
+        }
+
+        // batches cross internally and move q by the net flow; fpmm reserves can't
+        require!(
+            (0..=MAX_BATCH_SECONDS).contains(&batch_seconds),
+            ErrorCode::InvalidBatchConfig
+        );
+        if batch_seconds > 0 {
+            require!(
+                pricing_model != PRICING_FPMM && market_type != MARKET_TYPE_PARIMUTUEL,
+                ErrorCode::InvalidBatchConfig
+            );
+            // batches already hide order flow until the clear
+            require!(!commit_reveal, ErrorCode::InvalidBatchConfig);
+        }
+
         let market = &mut ctx.accounts.market;
+        let outcomes = &mut ctx.accounts.market_outcomes;
 
         market.creator = ctx.accounts.creator.key();
         market.resolution_time = resolution_time;
 
         market.market_type = market_type;
         market.outcome_count = outcome_names.len() as u8;
-        market.outcome_names = outcome_names;
 
         market.b_lamports = b_lamports; // stored (compat)
-        market.q = [0u64; MAX_OUTCOMES];
+
+        // linear curve
+        market.base_price_lamports = base_price_lamports;
+        market.slope_lamports_per_supply = slope_lamports_per_supply;
+
+        // per-outcome state lives in the outcomes account
+        outcomes.market = market.key();
+        outcomes.q = vec![0u64; outcome_names.len()];
+        outcomes.outcome_cost = vec![0u64; outcome_names.len()];
+        outcomes.reserves = vec![0u64; outcome_names.len()];
+        outcomes.price_cumulative = vec![0u128; outcome_names.len()];
+        outcomes.outcome_names = outcome_names;
 
         // lifecycle
         market.status = MarketStatus::Open;
@@ -116,11 +314,57 @@ pub mod funmarket_pump {
         // fees escrow (creator only)
         market.creator_fee_escrow = 0;
 
+        // outcome-level voiding
+        market.voided_outcomes = 0;
+        market.void_reserve_lamports = 0;
+
+        // complete sets: fixed collateral per set (one base-price share per outcome)
+        market.complete_sets = 0;
+        market.set_price_lamports = base_price_lamports
+            .checked_mul(market.outcome_count as u64)
+            .ok_or(ErrorCode::Overflow)?;
+
         // anti-manip
         market.max_position_bps = max_position_bps;
         market.max_trade_shares = max_trade_shares;
         market.cooldown_seconds = cooldown_seconds;
 
+        // pricing (fpmm pool is seeded by add_liquidity, lmsr by deposit_subsidy)
+        market.pricing_model = pricing_model;
+        market.lp_fee_bps = lp_fee_bps;
+        market.lp_supply = 0;
+        market.lp_fee_per_share = 0;
+        market.lp_fee_pool_lamports = 0;
+        market.subsidy_lamports = 0;
+        market.claimed_winning_shares = 0;
+
+        // graduation
+        market.volume_lamports = 0;
+        market.graduation_volume_lamports = graduation_volume_lamports;
+        market.graduation_supply = graduation_supply;
+        market.graduation_bonus_bps = graduation_bonus_bps;
+        market.graduation_bonus_lamports = 0;
+        market.graduated = false;
+
+        // batch auction
+        market.batch_seconds = batch_seconds;
+
+        // commit-reveal
+        market.commit_reveal = commit_reveal;
+
+        // twap accumulators start now
+        market.twap_last_ts = now;
+
+        market.creator_fee_bps = creator_fee_bps;
+
+        // dynamic fee
+        market.dynamic_fee_max_bps = dynamic_fee_max_bps;
+        market.dynamic_fee_ramp_seconds = dynamic_fee_ramp_seconds;
+        market.volatility_ema_bps = 0;
+        market.volatility_last_ts = now;
+
+        market.fee_mode = fee_mode;
+
         emit!(MarketCreated {
             market: market.key(),
             creator: market.creator,
@@ -128,6 +372,10 @@ pub mod funmarket_pump {
             market_type,
             outcome_count: market.outcome_count,
             b_lamports,
+            pricing_model,
+            base_price_lamports,
+            slope_lamports_per_supply,
+            creator_fee_bps,
         });
 
         Ok(())
@@ -136,568 +384,4757 @@ pub mod funmarket_pump {
     /* ---------- TRADE ---------- */
 
     pub fn buy_shares(ctx: Context<Trade>, shares: u64, outcome_index: u8) -> Result<()> {
-        trade_inner(ctx, shares, outcome_index, true)
+        require!(!ctx.accounts.market.commit_reveal, ErrorCode::CommitRevealRequired);
+        trade_inner(ctx.accounts, shares, outcome_index, true, u64::MAX)
     }
 
     pub fn sell_shares(ctx: Context<Trade>, shares: u64, outcome_index: u8) -> Result<()> {
-        trade_inner(ctx, shares, outcome_index, false)
+        trade_inner(ctx.accounts, shares, outcome_index, false, u64::MAX)
     }
 
-    /* ---------- PROPOSE (creator) ---------- */
+    pub fn swap_outcome(
+        ctx: Context<Trade>,
+        from_outcome: u8,
+        to_outcome: u8,
+        shares: u64,
+        min_shares_out: u64,
+    ) -> Result<()> {
+        swap_inner(ctx, from_outcome, to_outcome, shares, min_shares_out)
+    }
+
+    pub fn batch_trade(
+        ctx: Context<Trade>,
+        legs: Vec<TradeLeg>,
+        max_pay_lamports: u64,
+        min_receive_lamports: u64,
+    ) -> Result<()> {
+        batch_inner(ctx, legs, max_pay_lamports, min_receive_lamports)
+    }
+
+    /* ---------- COMPLETE SETS ---------- */
+
+    pub fn mint_complete_set(ctx: Context<CompleteSet>, sets: u64) -> Result<()> {
+        let trader_key = ctx.accounts.trader.key();
+        let market_key = ctx.accounts.market.key();
+
+        let trader_ai = ctx.accounts.trader.to_account_info();
+        let market_ai = ctx.accounts.market.to_account_info();
+        let pos_ai = ctx.accounts.user_position.to_account_info();
+        let system_ai = ctx.accounts.system_program.to_account_info();
 
-    pub fn propose_resolution(ctx: Context<ProposeResolution>, proposed_outcome: u8) -> Result<()> {
         let market = &mut ctx.accounts.market;
-        let now = Clock::get()?.unix_timestamp;
+        let outcomes = &mut ctx.accounts.market_outcomes;
+        let pos = &mut ctx.accounts.user_position;
 
-        require!(market.status == MarketStatus::Open, ErrorCode::InvalidState);
-        require!(!market.cancelled, ErrorCode::InvalidState);
-        require!(!market.resolved, ErrorCode::MarketResolved);
+        require_market_tradable(market)?;
+        require_complete_sets_enabled(market)?;
+        require!(sets > 0, ErrorCode::InvalidShares);
+        require!(sets <= market.max_trade_shares, ErrorCode::TradeTooLarge);
 
-        require!(now >= market.resolution_time, ErrorCode::MarketNotEnded);
+        init_or_check_position(pos, market_key, trader_key)?;
 
-        // 24h window to propose after end
-        let cutoff = market
-            .resolution_time
-            .checked_add(CREATOR_PROPOSE_WINDOW)
+        let amount = sets
+            .checked_mul(market.set_price_lamports)
             .ok_or(ErrorCode::Overflow)?;
-        require!(now <= cutoff, ErrorCode::TooLateToPropose);
 
-        let idx = proposed_outcome as usize;
-        require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+        invoke(
+            &system_instruction::transfer(&trader_key, &market_key, amount),
+            &[trader_ai.clone(), market_ai, system_ai.clone()],
+        )?;
 
-        market.status = MarketStatus::Proposed;
-        market.proposed_outcome = Some(proposed_outcome);
-        market.proposed_at = Some(now);
-        market.contest_deadline = Some(now.checked_add(DISPUTE_WINDOW).ok_or(ErrorCode::Overflow)?);
-        market.dispute_count = 0;
+        // one share of every outcome, off the curve (q is curve supply; complete_sets counts these)
+        let n = market.outcome_count as usize;
+        for idx in 0..n {
+            let basis = amount / n as u64 + if idx == 0 { amount % n as u64 } else { 0 };
+            let entry = pos.entry_mut(idx);
+            entry.shares = entry.shares.checked_add(sets).ok_or(ErrorCode::Overflow)?;
+            entry.cost_lamports = entry.cost_lamports.checked_add(basis).ok_or(ErrorCode::Overflow)?;
+            outcomes.outcome_cost[idx] = outcomes.outcome_cost[idx]
+                .checked_add(basis)
+                .ok_or(ErrorCode::Overflow)?;
+        }
 
-        emit!(ResolutionProposed {
+        market.complete_sets = market.complete_sets.checked_add(sets).ok_or(ErrorCode::Overflow)?;
+        pos.net_cost_lamports = pos
+            .net_cost_lamports
+            .checked_add(amount as i128)
+            .ok_or(ErrorCode::Overflow)?;
+
+        let needed = 8 + pos.try_to_vec()?.len();
+        grow_account(&pos_ai, &trader_ai, &system_ai, needed)?;
+
+        emit!(CompleteSetTraded {
             market: market.key(),
-            proposed_outcome,
-            proposed_at: now,
-            contest_deadline: market.contest_deadline.unwrap(),
+            user: trader_key,
+            is_mint: true,
+            sets,
+            amount_lamports: amount,
         });
 
         Ok(())
     }
 
-    /* ---------- DISPUTE (any user during contest window) ---------- */
+    pub fn redeem_complete_set(ctx: Context<CompleteSet>, sets: u64) -> Result<()> {
+        let trader_key = ctx.accounts.trader.key();
+        let market_key = ctx.accounts.market.key();
+
+        let trader_ai = ctx.accounts.trader.to_account_info();
+        let market_ai = ctx.accounts.market.to_account_info();
 
-    pub fn dispute(ctx: Context<Dispute>) -> Result<()> {
         let market = &mut ctx.accounts.market;
-        let now = Clock::get()?.unix_timestamp;
+        let outcomes = &mut ctx.accounts.market_outcomes;
+        let pos = &mut ctx.accounts.user_position;
 
-        require!(market.status == MarketStatus::Proposed, ErrorCode::InvalidState);
-        require!(!market.cancelled, ErrorCode::InvalidState);
-        require!(!market.resolved, ErrorCode::MarketResolved);
+        let now = require_market_tradable(market)?;
+        require_complete_sets_enabled(market)?;
+        require!(sets > 0, ErrorCode::InvalidShares);
 
-        let deadline = market.contest_deadline.ok_or(ErrorCode::InvalidState)?;
-        require!(now < deadline, ErrorCode::DisputeWindowClosed);
+        require!(pos.market == market_key, ErrorCode::InvalidUserPosition);
+        require!(pos.user == trader_key, ErrorCode::InvalidUserPosition);
 
-        market.dispute_count = market.dispute_count.saturating_add(1);
+        accrue_twap(market, outcomes, now)?;
+
+        // burn one share of every outcome per set; minted (off-curve) supply goes first,
+        // the rest comes out of curve supply
+        let from_minted = sets.min(market.complete_sets);
+        let from_curve = sets - from_minted;
+
+        for idx in 0..(market.outcome_count as usize) {
+            require!(pos.shares_of(idx) >= sets, ErrorCode::NotEnoughShares);
+            require!(outcomes.q[idx] >= from_curve, ErrorCode::InsufficientShares);
+
+            let entry = pos.entry_mut(idx);
+            // release cost basis pro rata to the shares burned (all of it when emptied)
+            let basis_out = if entry.shares == sets {
+                entry.cost_lamports
+            } else {
+                ((entry.cost_lamports as u128 * sets as u128) / entry.shares as u128) as u64
+            };
+
+            entry.shares -= sets;
+            entry.cost_lamports -= basis_out;
+            if entry.shares == 0 {
+                pos.remove_entry(idx);
+            }
+
+            outcomes.q[idx] -= from_curve;
+            outcomes.outcome_cost[idx] = outcomes.outcome_cost[idx].saturating_sub(basis_out);
+        }
 
-        emit!(Disputed {
+        market.complete_sets -= from_minted;
+
+        let amount = sets
+            .checked_mul(market.set_price_lamports)
+            .ok_or(ErrorCode::Overflow)?;
+        require!(
+            market_ai.lamports().saturating_sub(reserved_lamports(market)?) >= amount,
+            ErrorCode::InsufficientMarketBalance
+        );
+
+        **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(amount);
+        **trader_ai.try_borrow_mut_lamports()? = trader_ai.lamports().saturating_add(amount);
+
+        pos.net_cost_lamports = pos
+            .net_cost_lamports
+            .checked_sub(amount as i128)
+            .ok_or(ErrorCode::Overflow)?
+            .max(0);
+
+        if from_curve > 0 {
+            record_candle(&ctx.accounts.price_history, market, outcomes, now, &[])?;
+        }
+
+        emit!(CompleteSetTraded {
             market: market.key(),
-            by: ctx.accounts.user.key(),
-            dispute_count: market.dispute_count,
+            user: trader_key,
+            is_mint: false,
+            sets,
+            amount_lamports: amount,
         });
 
         Ok(())
     }
 
-    /* ---------- ADMIN FINALIZE (0 disputes) ---------- */
+    /* ---------- LIQUIDITY (fpmm) ---------- */
+
+    pub fn add_liquidity(ctx: Context<Liquidity>, sets: u64) -> Result<()> {
+        let provider_key = ctx.accounts.provider.key();
+        let market_key = ctx.accounts.market.key();
+
+        let provider_ai = ctx.accounts.provider.to_account_info();
+        let market_ai = ctx.accounts.market.to_account_info();
+        let pos_ai = ctx.accounts.user_position.to_account_info();
+        let system_ai = ctx.accounts.system_program.to_account_info();
 
-    pub fn admin_finalize_no_disputes(ctx: Context<AdminResolve>) -> Result<()> {
         let market = &mut ctx.accounts.market;
-        let now = Clock::get()?.unix_timestamp;
+        let outcomes = &mut ctx.accounts.market_outcomes;
+        let lp = &mut ctx.accounts.liquidity_position;
+        let pos = &mut ctx.accounts.user_position;
 
-        require_keys_eq!(ctx.accounts.admin.key(), ADMIN_AUTHORITY, ErrorCode::Unauthorized);
+        let now = require_market_tradable(market)?;
+        require!(market.pricing_model == PRICING_FPMM, ErrorCode::InvalidPricingModel);
+        require!(sets > 0, ErrorCode::InvalidShares);
 
-        require!(market.status == MarketStatus::Proposed, ErrorCode::InvalidState);
-        require!(!market.resolved, ErrorCode::MarketResolved);
-        require!(!market.cancelled, ErrorCode::InvalidState);
+        init_or_check_position(pos, market_key, provider_key)?;
+        init_or_check_lp_position(lp, market_key, provider_key)?;
+        settle_lp_fees(market, lp)?;
+        accrue_twap(market, outcomes, now)?;
 
-        let deadline = market.contest_deadline.ok_or(ErrorCode::InvalidState)?;
-        require!(now >= deadline, ErrorCode::TooEarly);
+        let amount = sets
+            .checked_mul(market.set_price_lamports)
+            .ok_or(ErrorCode::Overflow)?;
 
-        require!(market.dispute_count == 0, ErrorCode::HasDisputes);
+        invoke(
+            &system_instruction::transfer(&provider_key, &market_key, amount),
+            &[provider_ai.clone(), market_ai, system_ai.clone()],
+        )?;
 
-        let out = market.proposed_outcome.ok_or(ErrorCode::InvalidState)?;
+        let minted = if market.lp_supply == 0 {
+            // first deposit seeds an even pool
+            for r in outcomes.reserves.iter_mut() {
+                *r = r.checked_add(sets).ok_or(ErrorCode::Overflow)?;
+            }
+            sets
+        } else {
+            // add in proportion to the heaviest reserve; the rest is handed back as shares
+            let pool_weight = outcomes.reserves.iter().copied().max().unwrap_or(0);
+            require!(pool_weight > 0, ErrorCode::NoLiquidity);
+
+            for idx in 0..outcomes.reserves.len() {
+                let added = (sets as u128)
+                    .checked_mul(outcomes.reserves[idx] as u128)
+                    .ok_or(ErrorCode::Overflow)?
+                    / pool_weight as u128;
+                let added = added as u64;
+                outcomes.reserves[idx] = outcomes.reserves[idx]
+                    .checked_add(added)
+                    .ok_or(ErrorCode::Overflow)?;
+                credit_shares(outcomes, pos, idx, sets - added)?;
+            }
+
+            u64::try_from(
+                (sets as u128)
+                    .checked_mul(market.lp_supply as u128)
+                    .ok_or(ErrorCode::Overflow)?
+                    / pool_weight as u128,
+            )
+            .map_err(|_| error!(ErrorCode::Overflow))?
+        };
+        require!(minted > 0, ErrorCode::InvalidShares);
+
+        market.lp_supply = market.lp_supply.checked_add(minted).ok_or(ErrorCode::Overflow)?;
+        lp.lp_shares = lp.lp_shares.checked_add(minted).ok_or(ErrorCode::Overflow)?;
+        lp.net_deposit_lamports = lp
+            .net_deposit_lamports
+            .checked_add(amount)
+            .ok_or(ErrorCode::Overflow)?;
+        reset_lp_fee_debt(market, lp)?;
 
-        market.status = MarketStatus::Finalized;
-        market.resolved = true;
-        market.winning_outcome = Some(out);
+        let needed = 8 + pos.try_to_vec()?.len();
+        grow_account(&pos_ai, &provider_ai, &system_ai, needed)?;
 
-        emit!(Finalized {
+        record_candle(&ctx.accounts.price_history, market, outcomes, now, &[])?;
+
+        emit!(LiquidityChanged {
             market: market.key(),
-            winning_outcome: out,
-            by: ctx.accounts.admin.key(),
+            provider: provider_key,
+            is_add: true,
+            lp_shares: minted,
+            amount_lamports: amount,
         });
 
         Ok(())
     }
 
-    /* ---------- ADMIN FINALIZE (with disputes) ---------- */
+    pub fn remove_liquidity(ctx: Context<Liquidity>, lp_shares: u64) -> Result<()> {
+        let provider_key = ctx.accounts.provider.key();
+        let market_key = ctx.accounts.market.key();
+
+        let provider_ai = ctx.accounts.provider.to_account_info();
+        let market_ai = ctx.accounts.market.to_account_info();
+        let pos_ai = ctx.accounts.user_position.to_account_info();
+        let system_ai = ctx.accounts.system_program.to_account_info();
 
-    pub fn admin_finalize(ctx: Context<AdminResolve>, winning_outcome: u8) -> Result<()> {
         let market = &mut ctx.accounts.market;
-        let now = Clock::get()?.unix_timestamp;
+        let outcomes = &mut ctx.accounts.market_outcomes;
+        let lp = &mut ctx.accounts.liquidity_position;
+        let pos = &mut ctx.accounts.user_position;
 
-        require_keys_eq!(ctx.accounts.admin.key(), ADMIN_AUTHORITY, ErrorCode::Unauthorized);
+        require!(market.pricing_model == PRICING_FPMM, ErrorCode::InvalidPricingModel);
+        require!(lp_shares > 0, ErrorCode::InvalidShares);
 
-        require!(market.status == MarketStatus::Proposed, ErrorCode::InvalidState);
-        require!(!market.resolved, ErrorCode::MarketResolved);
-        require!(!market.cancelled, ErrorCode::InvalidState);
+        require!(lp.market == market_key, ErrorCode::InvalidUserPosition);
+        require!(lp.owner == provider_key, ErrorCode::InvalidUserPosition);
+        require!(lp.lp_shares >= lp_shares, ErrorCode::InsufficientShares);
 
-        let deadline = market.contest_deadline.ok_or(ErrorCode::InvalidState)?;
-        require!(now >= deadline, ErrorCode::TooEarly);
+        init_or_check_position(pos, market_key, provider_key)?;
+        settle_lp_fees(market, lp)?;
 
-        require!(market.dispute_count > 0, ErrorCode::NoDispute);
+        let now = Clock::get()?.unix_timestamp;
+        if market.status == MarketStatus::Open {
+            accrue_twap(market, outcomes, now)?;
+        }
 
-        let idx = winning_outcome as usize;
-        require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+        let deposit_out = u64::try_from(
+            (lp.net_deposit_lamports as u128)
+                .checked_mul(lp_shares as u128)
+                .ok_or(ErrorCode::Overflow)?
+                / lp.lp_shares as u128,
+        )
+        .map_err(|_| error!(ErrorCode::Overflow))?;
+
+        // pro-rata slice of every reserve
+        let mut out = Vec::with_capacity(outcomes.reserves.len());
+        for r in outcomes.reserves.iter_mut() {
+            let share = u64::try_from(
+                (*r as u128)
+                    .checked_mul(lp_shares as u128)
+                    .ok_or(ErrorCode::Overflow)?
+                    / market.lp_supply as u128,
+            )
+            .map_err(|_| error!(ErrorCode::Overflow))?;
+            *r -= share;
+            out.push(share);
+        }
 
-        market.status = MarketStatus::Finalized;
-        market.resolved = true;
-        market.winning_outcome = Some(winning_outcome);
+        let amount = match market.status {
+            MarketStatus::Open => {
+                // merge what forms complete sets back into collateral, hand out the rest
+                let merged = out.iter().copied().min().unwrap_or(0);
+                for (idx, share) in out.iter().enumerate() {
+                    credit_shares(outcomes, pos, idx, share - merged)?;
+                }
+                merged
+                    .checked_mul(market.set_price_lamports)
+                    .ok_or(ErrorCode::Overflow)?
+            }
+            MarketStatus::Finalized => {
+                // the winning reserve is paid like a claim, whole stake at once, apart from
+                // the provider's trader position (which may already be claimed)
+                require!(!lp.reserves_redeemed, ErrorCode::AlreadyClaimed);
+                require!(lp_shares == lp.lp_shares, ErrorCode::InvalidShares);
+                lp.reserves_redeemed = true;
+
+                let winning = market.winning_outcome.ok_or(ErrorCode::MarketNotResolved)? as usize;
+                pro_rata_payout(out[winning], market.winning_pool_lamports, market.winning_supply)?
+            }
+            // cancelled: deposits are refunded like trader cost basis
+            MarketStatus::Cancelled => deposit_out,
+            MarketStatus::Proposed => return err!(ErrorCode::InvalidState),
+        };
+
+        if amount > 0 {
+            require!(market_ai.lamports() >= amount, ErrorCode::InsufficientMarketBalance);
+            **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(amount);
+            **provider_ai.try_borrow_mut_lamports()? = provider_ai.lamports().saturating_add(amount);
+        }
 
-        emit!(Finalized {
+        market.lp_supply -= lp_shares;
+        lp.lp_shares -= lp_shares;
+        lp.net_deposit_lamports = lp.net_deposit_lamports.saturating_sub(deposit_out);
+        reset_lp_fee_debt(market, lp)?;
+
+        let needed = 8 + pos.try_to_vec()?.len();
+        grow_account(&pos_ai, &provider_ai, &system_ai, needed)?;
+
+        if market.status == MarketStatus::Open {
+            record_candle(&ctx.accounts.price_history, market, outcomes, now, &[])?;
+        }
+
+        emit!(LiquidityChanged {
             market: market.key(),
-            winning_outcome,
-            by: ctx.accounts.admin.key(),
+            provider: provider_key,
+            is_add: false,
+            lp_shares,
+            amount_lamports: amount,
         });
 
         Ok(())
     }
 
-    /* ---------- ADMIN CANCEL (with disputes) ---------- */
+    pub fn claim_lp_fees(ctx: Context<ClaimLpFees>) -> Result<()> {
+        let market_ai = ctx.accounts.market.to_account_info();
+        let provider_ai = ctx.accounts.provider.to_account_info();
 
-    pub fn admin_cancel(ctx: Context<AdminResolve>) -> Result<()> {
         let market = &mut ctx.accounts.market;
-        let now = Clock::get()?.unix_timestamp;
+        let lp = &mut ctx.accounts.liquidity_position;
 
-        require_keys_eq!(ctx.accounts.admin.key(), ADMIN_AUTHORITY, ErrorCode::Unauthorized);
+        // earned fees sit outside the cost basis refunds, so they stay claimable on cancel
+        require!(lp.owner == ctx.accounts.provider.key(), ErrorCode::Unauthorized);
 
-        require!(market.status == MarketStatus::Proposed, ErrorCode::InvalidState);
-        require!(!market.resolved, ErrorCode::MarketResolved);
-        require!(!market.cancelled, ErrorCode::InvalidState);
+        settle_lp_fees(market, lp)?;
 
-        let deadline = market.contest_deadline.ok_or(ErrorCode::InvalidState)?;
-        require!(now >= deadline, ErrorCode::TooEarly);
+        let amount = lp.fees_owed.min(market.lp_fee_pool_lamports);
+        require!(amount > 0, ErrorCode::NothingToClaim);
+        require!(market_ai.lamports() >= amount, ErrorCode::InsufficientMarketBalance);
 
-        require!(market.dispute_count > 0, ErrorCode::NoDispute);
+        **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(amount);
+        **provider_ai.try_borrow_mut_lamports()? = provider_ai.lamports().saturating_add(amount);
 
-        market.status = MarketStatus::Cancelled;
-        market.cancelled = true;
+        lp.fees_owed -= amount;
+        market.lp_fee_pool_lamports -= amount;
 
-        emit!(Cancelled {
+        emit!(LpFeesClaimed {
             market: market.key(),
-            by: ctx.accounts.admin.key(),
-            reason: CancelReason::Admin,
+            provider: lp.owner,
+            amount_lamports: amount,
         });
 
         Ok(())
     }
 
-    /* ---------- ADMIN CANCEL (no proposal after 24h) ---------- */
+    /* ---------- SUBSIDY (lmsr) ---------- */
+
+    pub fn deposit_subsidy(ctx: Context<Subsidy>, amount_lamports: u64) -> Result<()> {
+        let provider_key = ctx.accounts.provider.key();
+        let market_key = ctx.accounts.market.key();
+
+        let provider_ai = ctx.accounts.provider.to_account_info();
+        let market_ai = ctx.accounts.market.to_account_info();
+        let system_ai = ctx.accounts.system_program.to_account_info();
 
-    pub fn admin_cancel_no_proposal(ctx: Context<AdminResolve>) -> Result<()> {
         let market = &mut ctx.accounts.market;
-        let now = Clock::get()?.unix_timestamp;
+        let outcomes = &mut ctx.accounts.market_outcomes;
+        let lp = &mut ctx.accounts.liquidity_position;
 
-        require_keys_eq!(ctx.accounts.admin.key(), ADMIN_AUTHORITY, ErrorCode::Unauthorized);
+        require_market_tradable(market)?;
+        require!(market.uses_subsidy(), ErrorCode::InvalidPricingModel);
+        // graduation liquidity is locked; outside LPs would dilute it
+        require!(!market.graduated, ErrorCode::AlreadyGraduated);
+        require!(amount_lamports > 0, ErrorCode::InvalidB);
 
-        require!(market.status == MarketStatus::Open, ErrorCode::InvalidState);
-        require!(!market.resolved, ErrorCode::MarketResolved);
-        require!(!market.cancelled, ErrorCode::InvalidState);
+        init_or_check_lp_position(lp, market_key, provider_key)?;
+        settle_lp_fees(market, lp)?;
 
-        require!(now >= market.resolution_time, ErrorCode::MarketNotEnded);
+        invoke(
+            &system_instruction::transfer(&provider_key, &market_key, amount_lamports),
+            &[provider_ai, market_ai, system_ai],
+        )?;
 
-        let cutoff = market
-            .resolution_time
-            .checked_add(CREATOR_PROPOSE_WINDOW)
+        let old_b = market.b_lamports;
+        let old_subsidy = market.subsidy_lamports;
+        let new_subsidy = old_subsidy
+            .checked_add(amount_lamports)
             .ok_or(ErrorCode::Overflow)?;
-        require!(now >= cutoff, ErrorCode::TooEarly);
 
-        market.status = MarketStatus::Cancelled;
-        market.cancelled = true;
+        // prices stay put if q_eff scales with b (lmsr) or with the subsidy (ls-lmsr,
+        // whose cost is homogeneous in q)
+        let (scale_num, scale_den) = if market.pricing_model == PRICING_LS_LMSR {
+            (new_subsidy, old_subsidy)
+        } else {
+            // worst-case loss is b * ln(n), so the subsidy buys b = subsidy / ln(n)
+            let new_b = lmsr_b_for_subsidy(new_subsidy, market.outcome_count)?;
+            require!(new_b > 0, ErrorCode::InvalidB);
+            market.b_lamports = new_b;
+            (new_b, old_b)
+        };
+
+        if old_subsidy > 0 {
+            for idx in 0..outcomes.reserves.len() {
+                let q_eff = (outcomes.q[idx] as u128)
+                    .checked_add(outcomes.reserves[idx] as u128)
+                    .ok_or(ErrorCode::Overflow)?;
+                let scaled = q_eff
+                    .checked_mul(scale_num as u128)
+                    .ok_or(ErrorCode::Overflow)?
+                    / scale_den as u128;
+                let r = scaled.saturating_sub(outcomes.q[idx] as u128);
+                outcomes.reserves[idx] = u64::try_from(r).map_err(|_| error!(ErrorCode::Overflow))?;
+            }
+        } else if market.pricing_model == PRICING_LS_LMSR {
+            // ls-lmsr needs inventory: worst-case loss on an even seed is vig * seed
+            let seed = ls_lmsr_seed_shares(market, new_subsidy)?;
+            require!(seed > 0, ErrorCode::InvalidB);
+            outcomes.reserves.iter_mut().for_each(|r| *r = seed);
+        }
 
-        emit!(Cancelled {
+        let minted = if market.lp_supply == 0 {
+            amount_lamports
+        } else {
+            u64::try_from(
+                (amount_lamports as u128)
+                    .checked_mul(market.lp_supply as u128)
+                    .ok_or(ErrorCode::Overflow)?
+                    / old_subsidy as u128,
+            )
+            .map_err(|_| error!(ErrorCode::Overflow))?
+        };
+        require!(minted > 0, ErrorCode::InvalidShares);
+
+        market.subsidy_lamports = new_subsidy;
+        market.lp_supply = market.lp_supply.checked_add(minted).ok_or(ErrorCode::Overflow)?;
+        lp.lp_shares = lp.lp_shares.checked_add(minted).ok_or(ErrorCode::Overflow)?;
+        lp.net_deposit_lamports = lp
+            .net_deposit_lamports
+            .checked_add(amount_lamports)
+            .ok_or(ErrorCode::Overflow)?;
+        reset_lp_fee_debt(market, lp)?;
+
+        emit!(LiquidityChanged {
             market: market.key(),
-            by: ctx.accounts.admin.key(),
-            reason: CancelReason::NoProposal24h,
+            provider: provider_key,
+            is_add: true,
+            lp_shares: minted,
+            amount_lamports,
         });
 
         Ok(())
     }
 
-    /* ---------- CLAIM CREATOR FEES (escrow) ---------- */
+    pub fn withdraw_subsidy(ctx: Context<Subsidy>) -> Result<()> {
+        let provider_key = ctx.accounts.provider.key();
+        let market_key = ctx.accounts.market.key();
 
-    pub fn claim_creator_fees(ctx: Context<ClaimCreatorFees>) -> Result<()> {
+        let provider_ai = ctx.accounts.provider.to_account_info();
         let market_ai = ctx.accounts.market.to_account_info();
-        let creator_ai = ctx.accounts.creator.to_account_info();
 
         let market = &mut ctx.accounts.market;
+        let outcomes = &ctx.accounts.market_outcomes;
+        let lp = &mut ctx.accounts.liquidity_position;
+
+        require!(market.uses_subsidy(), ErrorCode::InvalidPricingModel);
+        require!(lp.market == market_key, ErrorCode::InvalidUserPosition);
+        require!(lp.owner == provider_key, ErrorCode::InvalidUserPosition);
+        require!(lp.lp_shares > 0, ErrorCode::NothingToClaim);
+
+        settle_lp_fees(market, lp)?;
+
+        let shares = lp.lp_shares;
+        let fees = lp.fees_owed.min(market.lp_fee_pool_lamports);
+
+        let amount = match market.status {
+            MarketStatus::Finalized => {
+                let residual = subsidy_residual(market, &market_ai, outcomes)?;
+                let share = (residual as u128)
+                    .checked_mul(shares as u128)
+                    .ok_or(ErrorCode::Overflow)?
+                    / market.lp_supply as u128;
+                (share as u64).checked_add(fees).ok_or(ErrorCode::Overflow)?
+            }
+            // cancelled: deposits are refunded like trader cost basis
+            MarketStatus::Cancelled => lp.net_deposit_lamports,
+            _ => return err!(ErrorCode::InvalidState),
+        };
+
+        if amount > 0 {
+            require!(market_ai.lamports() >= amount, ErrorCode::InsufficientMarketBalance);
+            **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(amount);
+            **provider_ai.try_borrow_mut_lamports()? = provider_ai.lamports().saturating_add(amount);
+        }
 
-        // only creator
-        require_keys_eq!(ctx.accounts.creator.key(), market.creator, ErrorCode::Unauthorized);
-
-        // only if finalized (never on cancelled)
-        require!(market.status == MarketStatus::Finalized, ErrorCode::InvalidState);
-        require!(market.resolved, ErrorCode::MarketNotResolved);
-        require!(!market.cancelled, ErrorCode::InvalidState);
+        if market.status == MarketStatus::Finalized {
+            market.lp_fee_pool_lamports -= fees;
+            lp.fees_owed -= fees;
+        }
 
-        let amount = market.creator_fee_escrow;
-        require!(amount > 0, ErrorCode::NothingToClaim);
-        require!(market_ai.lamports() >= amount, ErrorCode::InsufficientMarketBalance);
+        market.lp_supply -= shares;
+        lp.lp_shares = 0;
+        lp.net_deposit_lamports = 0;
+        reset_lp_fee_debt(market, lp)?;
 
-        **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(amount);
-        **creator_ai.try_borrow_mut_lamports()? = creator_ai.lamports().saturating_add(amount);
-
-        market.creator_fee_escrow = 0;
-
-        emit!(CreatorFeesClaimed {
+        emit!(LiquidityChanged {
             market: market.key(),
-            creator: ctx.accounts.creator.key(),
+            provider: provider_key,
+            is_add: false,
+            lp_shares: shares,
             amount_lamports: amount,
         });
 
         Ok(())
     }
 
-    /* ---------- CLAIM WINNINGS ---------- */
+    /* ---------- LIMIT ORDERS ---------- */
+
+    /// Rests an order for keepers. Buys escrow lamports for every share at the
+    /// limit plus fees; sells move the shares (and their cost basis) out of the position.
+    pub fn place_limit_order(
+        ctx: Context<PlaceLimitOrder>,
+        order_id: u64,
+        outcome_index: u8,
+        is_buy: bool,
+        shares: u64,
+        limit_price_lamports: u64, // average per share: max for buys, min for sells
+        expiry_ts: i64,
+    ) -> Result<()> {
+        let owner_key = ctx.accounts.owner.key();
+        let market_key = ctx.accounts.market.key();
+        let order_key = ctx.accounts.limit_order.key();
+
+        let owner_ai = ctx.accounts.owner.to_account_info();
+        let order_ai = ctx.accounts.limit_order.to_account_info();
+        let system_ai = ctx.accounts.system_program.to_account_info();
+
+        let market = &ctx.accounts.market;
+        let order = &mut ctx.accounts.limit_order;
+        let pos = &mut ctx.accounts.user_position;
+
+        let now = require_market_tradable(market)?;
+        require!(market.batch_seconds == 0, ErrorCode::BatchAuctionOnly);
+        require!(!(is_buy && market.commit_reveal), ErrorCode::CommitRevealRequired);
+        require!(is_buy || !market.is_parimutuel(), ErrorCode::SellDisabled);
+
+        let idx = outcome_index as usize;
+        require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+        require!(!market.is_voided(idx), ErrorCode::OutcomeVoided);
+        require!(shares > 0, ErrorCode::InvalidShares);
+        require!(shares <= market.max_trade_shares, ErrorCode::TradeTooLarge);
+        require!(expiry_ts > now, ErrorCode::OrderExpired);
+
+        init_or_check_position(pos, market_key, owner_key)?;
+        // the referrer is fixed at placement so the filling keeper can't pick one
+        referral_share(&ctx.accounts.referrer, owner_key)?;
+
+        let mut deposit = LIMIT_ORDER_TIP_LAMPORTS;
+        let mut escrow_lamports = 0u64;
+        let mut escrow_cost_lamports = 0u64;
+
+        if is_buy {
+            require!(limit_price_lamports > 0, ErrorCode::InvalidCost);
+            let notional = shares
+                .checked_mul(limit_price_lamports)
+                .ok_or(ErrorCode::Overflow)?;
+            escrow_lamports = notional
+                .checked_add(trade_fees_with(market, notional, &FeeDiscount::worst_case(market)).total())
+                .ok_or(ErrorCode::Overflow)?;
+            deposit = deposit.checked_add(escrow_lamports).ok_or(ErrorCode::Overflow)?;
+        } else {
+            escrow_cost_lamports = escrow_shares_out(pos, idx, shares)?;
+        }
+
+        invoke(
+            &system_instruction::transfer(&owner_key, &order_key, deposit),
+            &[owner_ai, order_ai, system_ai],
+        )?;
+
+        order.market = market_key;
+        order.owner = owner_key;
+        order.order_id = order_id;
+        order.outcome_index = outcome_index;
+        order.is_buy = is_buy;
+        order.shares = shares;
+        order.limit_price_lamports = limit_price_lamports;
+        order.expiry_ts = expiry_ts;
+        order.escrow_lamports = escrow_lamports;
+        order.escrow_cost_lamports = escrow_cost_lamports;
+        order.tip_lamports = LIMIT_ORDER_TIP_LAMPORTS;
+        order.referrer = ctx.accounts.referrer.as_ref().map(|r| r.key()).unwrap_or_default();
+
+        emit!(LimitOrderPlaced {
+            market: market_key,
+            owner: owner_key,
+            order: order_key,
+            outcome_index,
+            is_buy,
+            shares,
+            limit_price_lamports,
+            expiry_ts,
+        });
+
+        Ok(())
+    }
+
+    /// Permissionless keeper crank: fills the whole order through the curve when the
+    /// average price meets the limit, then closes it to the owner.
+    pub fn fill_order(ctx: Context<FillOrder>) -> Result<()> {
+        let market_key = ctx.accounts.market.key();
+        let order_key = ctx.accounts.limit_order.key();
 
-    pub fn claim_winnings(ctx: Context<ClaimWinnings>) -> Result<()> {
         let market_ai = ctx.accounts.market.to_account_info();
-        let user_ai = ctx.accounts.user.to_account_info();
+        let order_ai = ctx.accounts.limit_order.to_account_info();
+        let pos_ai = ctx.accounts.user_position.to_account_info();
+        let owner_ai = ctx.accounts.owner.to_account_info();
+        let keeper_ai = ctx.accounts.keeper.to_account_info();
+        let treasury_ai = ctx.accounts.treasury.to_account_info();
 
         let market = &mut ctx.accounts.market;
+        let outcomes = &mut ctx.accounts.market_outcomes;
+        let order = &ctx.accounts.limit_order;
         let pos = &mut ctx.accounts.user_position;
 
-        require!(market.resolved, ErrorCode::MarketNotResolved);
-        require!(market.status == MarketStatus::Finalized, ErrorCode::InvalidState);
-        require!(!market.cancelled, ErrorCode::InvalidState);
+        let now = require_market_tradable(market)?;
+        require!(market.batch_seconds == 0, ErrorCode::BatchAuctionOnly);
+        require!(now <= order.expiry_ts, ErrorCode::OrderExpired);
 
-        require!(!pos.claimed, ErrorCode::AlreadyClaimed);
+        accrue_twap(market, outcomes, now)?;
 
-        require!(pos.market == market.key(), ErrorCode::InvalidUserPosition);
-        require!(pos.user == ctx.accounts.user.key(), ErrorCode::InvalidUserPosition);
+        if market.cooldown_seconds > 0 && pos.last_trade_ts > 0 {
+            require!(
+                now - pos.last_trade_ts >= market.cooldown_seconds,
+                ErrorCode::CooldownActive
+            );
+        }
 
-        let winning = market.winning_outcome.ok_or(ErrorCode::MarketNotResolved)? as usize;
+        let idx = order.outcome_index as usize;
+        let shares = order.shares;
+        require!(!market.is_voided(idx), ErrorCode::OutcomeVoided);
 
-        let user_shares = pos.shares[winning];
-        require!(user_shares > 0, ErrorCode::NoWinningShares);
+        let limit_total = (shares as u128)
+            .checked_mul(order.limit_price_lamports as u128)
+            .ok_or(ErrorCode::Overflow)?;
 
-        let total_winning_supply = market.q[winning];
-        require!(total_winning_supply > 0, ErrorCode::InvalidSupply);
+        let referrer_key = ctx.accounts.referrer.as_ref().map(|r| r.key()).unwrap_or_default();
+        require_keys_eq!(referrer_key, order.referrer, ErrorCode::ReferrerMismatch);
+        let referral_bps = referral_share(&ctx.accounts.referrer, order.owner)?;
+        let discount = fee_discount(market, &ctx.accounts.protocol_config, &mut ctx.accounts.trader_stats, now);
+        let spot_before = spot_price_lamports(market, outcomes, idx).ok();
+
+        let (amount, fees) = if order.is_buy {
+            let cost = curve_buy_cost(market, outcomes, idx, shares)?;
+            require!(cost > 0, ErrorCode::InvalidCost);
+            require!(cost as u128 <= limit_total, ErrorCode::LimitNotReached);
+
+            let fees = trade_fees_with(market, cost, &discount).with_referral(referral_bps);
+            let total = cost.checked_add(fees.total()).ok_or(ErrorCode::Overflow)?;
+            require!(total <= order.escrow_lamports, ErrorCode::LimitNotReached);
+
+            **order_ai.try_borrow_mut_lamports()? = order_ai.lamports().saturating_sub(total);
+            **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_add(total);
+
+            collect_fees(market, &market_ai, &treasury_ai, &fees)?;
+            pay_referrer(&mut ctx.accounts.referrer, &market_ai, &fees)?;
+            record_buy(market, outcomes, pos, idx, shares, cost)?;
+            pos.net_cost_lamports = pos
+                .net_cost_lamports
+                .checked_add(cost as i128)
+                .ok_or(ErrorCode::Overflow)?;
 
-        let pool = market_ai.lamports();
+            enforce_position_cap(market, outcomes, pos, idx)?;
 
-        let payout = (user_shares as u128)
-            .checked_mul(pool as u128)
-            .ok_or(ErrorCode::Overflow)?
-            .checked_div(total_winning_supply as u128)
-            .ok_or(ErrorCode::Overflow)? as u64;
+            (cost, fees)
+        } else {
+            // escrowed shares go back into the position just to be sold
+            escrow_shares_in(pos, idx, shares, order.escrow_cost_lamports);
+            let (refund, fees) = sell_to_curve(
+                market, outcomes, pos, idx, shares, &discount, referral_bps, &market_ai, &owner_ai, &treasury_ai,
+            )?;
+            require!(refund as u128 >= limit_total, ErrorCode::LimitNotReached);
+            pay_referrer(&mut ctx.accounts.referrer, &market_ai, &fees)?;
 
-        require!(payout > 0, ErrorCode::InvalidPayout);
-        require!(pool >= payout, ErrorCode::InsufficientMarketBalance);
+            (refund, fees)
+        };
 
-        **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(payout);
-        **user_ai.try_borrow_mut_lamports()? = user_ai.lamports().saturating_add(payout);
+        record_candle(&ctx.accounts.price_history, market, outcomes, now, &[(idx, amount)])?;
+        record_volume(&mut ctx.accounts.trader_stats, amount, &fees, now);
+        track_volatility(market, outcomes, idx, spot_before, now);
 
-        pos.claimed = true;
+        pos.last_trade_ts = now;
 
-        emit!(Claimed {
-            market: market.key(),
-            user: ctx.accounts.user.key(),
-            kind: ClaimKind::Winnings,
-            amount_lamports: payout,
+        // a new outcome entry may not fit; the order's rent pays for it
+        let needed = 8 + pos.try_to_vec()?.len();
+        grow_account_from(&pos_ai, &order_ai, needed)?;
+
+        let tip = order.tip_lamports;
+        **order_ai.try_borrow_mut_lamports()? = order_ai.lamports().saturating_sub(tip);
+        **keeper_ai.try_borrow_mut_lamports()? = keeper_ai.lamports().saturating_add(tip);
+
+        emit!(TradeExecuted {
+            market: market_key,
+            user: order.owner,
+            is_buy: order.is_buy,
+            outcome_index: order.outcome_index,
+            shares,
+            amount_lamports: amount,
+            platform_fee_lamports: fees.platform,
+            creator_fee_lamports: fees.creator,
+            lp_fee_lamports: fees.lp,
+            referrer_fee_lamports: fees.referrer,
+            platform_fee_bps: fees.platform_bps as u16,
         });
 
+        emit!(LimitOrderFilled {
+            market: market_key,
+            owner: order.owner,
+            order: order_key,
+            keeper: ctx.accounts.keeper.key(),
+            amount_lamports: amount,
+            tip_lamports: tip,
+        });
+
+        // leftover escrow and rent back to the owner
+        ctx.accounts.limit_order.close(owner_ai)?;
+
         Ok(())
     }
 
-    /* ---------- CLAIM REFUND (cancelled) ---------- */
-
-    pub fn claim_refund(ctx: Context<ClaimRefund>) -> Result<()> {
-        let market_ai = ctx.accounts.market.to_account_info();
-        let user_ai = ctx.accounts.user.to_account_info();
+    /// Owner pulls a resting order; escrowed lamports or shares go back.
+    pub fn cancel_limit_order(ctx: Context<CancelLimitOrder>) -> Result<()> {
+        let pos_ai = ctx.accounts.user_position.to_account_info();
+        let owner_ai = ctx.accounts.owner.to_account_info();
+        let system_ai = ctx.accounts.system_program.to_account_info();
 
-        let market = &mut ctx.accounts.market;
+        let order = &ctx.accounts.limit_order;
         let pos = &mut ctx.accounts.user_position;
 
-        require!(market.cancelled, ErrorCode::NotCancelled);
-        require!(market.status == MarketStatus::Cancelled, ErrorCode::InvalidState);
-        require!(!market.resolved, ErrorCode::InvalidState);
+        if !order.is_buy {
+            escrow_shares_in(pos, order.outcome_index as usize, order.shares, order.escrow_cost_lamports);
 
-        require!(!pos.claimed, ErrorCode::AlreadyClaimed);
+            let needed = 8 + pos.try_to_vec()?.len();
+            grow_account(&pos_ai, &owner_ai, &system_ai, needed)?;
+        }
 
-        require!(pos.market == market.key(), ErrorCode::InvalidUserPosition);
-        require!(pos.user == ctx.accounts.user.key(), ErrorCode::InvalidUserPosition);
+        emit!(LimitOrderCancelled {
+            market: order.market,
+            owner: order.owner,
+            order: order.key(),
+        });
 
-        let nc = pos.net_cost_lamports;
-        require!(nc > 0, ErrorCode::NothingToRefund);
+        Ok(())
+    }
 
-        let refund_u64: u64 = u64::try_from(nc).map_err(|_| error!(ErrorCode::Overflow))?;
-        require!(market_ai.lamports() >= refund_u64, ErrorCode::InsufficientMarketBalance);
+    /* ---------- TRIGGER ORDERS ---------- */
+
+    /// Delegates up to `max_shares` of an outcome to a one-shot conditional exit:
+    /// sell once the spot price is at or below `stop_loss_price_lamports` or at or
+    /// above `take_profit_price_lamports` (0 disables a side), for no less than
+    /// `min_refund_lamports`.
+    pub fn place_trigger_order(
+        ctx: Context<PlaceTriggerOrder>,
+        trigger_id: u64,
+        outcome_index: u8,
+        max_shares: u64,
+        stop_loss_price_lamports: u64,
+        take_profit_price_lamports: u64,
+        min_refund_lamports: u64,
+    ) -> Result<()> {
+        let owner_key = ctx.accounts.owner.key();
+        let market_key = ctx.accounts.market.key();
+        let trigger_key = ctx.accounts.trigger_order.key();
 
-        **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(refund_u64);
-        **user_ai.try_borrow_mut_lamports()? = user_ai.lamports().saturating_add(refund_u64);
+        let market = &ctx.accounts.market;
+        let pos = &ctx.accounts.user_position;
+        let trigger = &mut ctx.accounts.trigger_order;
 
-        pos.claimed = true;
+        require_market_tradable(market)?;
+        require!(market.batch_seconds == 0, ErrorCode::BatchAuctionOnly);
+        require!(!market.is_parimutuel(), ErrorCode::SellDisabled);
 
-        emit!(Claimed {
-            market: market.key(),
-            user: ctx.accounts.user.key(),
-            kind: ClaimKind::Refund,
-            amount_lamports: refund_u64,
+        let idx = outcome_index as usize;
+        require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+        require!(max_shares > 0, ErrorCode::InvalidShares);
+        require!(pos.shares_of(idx) > 0, ErrorCode::NotEnoughShares);
+        require!(
+            stop_loss_price_lamports > 0 || take_profit_price_lamports > 0,
+            ErrorCode::InvalidTrigger
+        );
+        require!(
+            take_profit_price_lamports == 0 || take_profit_price_lamports > stop_loss_price_lamports,
+            ErrorCode::InvalidTrigger
+        );
+
+        invoke(
+            &system_instruction::transfer(&owner_key, &trigger_key, TRIGGER_TIP_LAMPORTS),
+            &[
+                ctx.accounts.owner.to_account_info(),
+                trigger.to_account_info(),
+                ctx.accounts.system_program.to_account_info(),
+            ],
+        )?;
+
+        trigger.market = market_key;
+        trigger.owner = owner_key;
+        trigger.user_position = pos.key();
+        trigger.trigger_id = trigger_id;
+        trigger.outcome_index = outcome_index;
+        trigger.max_shares = max_shares;
+        trigger.stop_loss_price_lamports = stop_loss_price_lamports;
+        trigger.take_profit_price_lamports = take_profit_price_lamports;
+        trigger.min_refund_lamports = min_refund_lamports;
+        trigger.tip_lamports = TRIGGER_TIP_LAMPORTS;
+
+        emit!(TriggerOrderPlaced {
+            market: market_key,
+            owner: owner_key,
+            trigger: trigger_key,
+            outcome_index,
+            max_shares,
+            stop_loss_price_lamports,
+            take_profit_price_lamports,
+            min_refund_lamports,
         });
 
         Ok(())
     }
-}
-
-/* ============================== PRICING HELPERS ============================== */
-
-fn linear_cost_lamports(start_supply: u64, shares: u64) -> Result<u64> {
-    require!(shares > 0, ErrorCode::InvalidShares);
 
-    let base = BASE_PRICE_LAMPORTS as u128;
-    let slope = SLOPE_LAMPORTS_PER_SUPPLY as u128;
+    /// Permissionless: sells min(max_shares, held) once the spot price crosses a trigger.
+    /// Cooldown does not apply to triggered exits.
+    pub fn execute_trigger(ctx: Context<ExecuteTrigger>) -> Result<()> {
+        let market_key = ctx.accounts.market.key();
+        let trigger_key = ctx.accounts.trigger_order.key();
 
-    let s = shares as u128;
-    let q0 = start_supply as u128;
-
-    let base_part = s.checked_mul(base).ok_or(ErrorCode::Overflow)?;
+        let market_ai = ctx.accounts.market.to_account_info();
+        let trigger_ai = ctx.accounts.trigger_order.to_account_info();
+        let owner_ai = ctx.accounts.owner.to_account_info();
+        let keeper_ai = ctx.accounts.keeper.to_account_info();
+        let treasury_ai = ctx.accounts.treasury.to_account_info();
 
-    let two_q0 = q0.checked_mul(2).ok_or(ErrorCode::Overflow)?;
-    let inside = two_q0
-        .checked_add(s.checked_sub(1).ok_or(ErrorCode::Overflow)?)
-        .ok_or(ErrorCode::Overflow)?;
-    let series = s
-        .checked_mul(inside)
-        .ok_or(ErrorCode::Overflow)?
-        .checked_div(2)
-        .ok_or(ErrorCode::Overflow)?;
+        let market = &mut ctx.accounts.market;
+        let outcomes = &mut ctx.accounts.market_outcomes;
+        let trigger = &ctx.accounts.trigger_order;
+        let pos = &mut ctx.accounts.user_position;
 
-    let slope_part = slope.checked_mul(series).ok_or(ErrorCode::Overflow)?;
+        let now = require_market_tradable(market)?;
+        require!(market.batch_seconds == 0, ErrorCode::BatchAuctionOnly);
 
-    let total = base_part.checked_add(slope_part).ok_or(ErrorCode::Overflow)?;
-    Ok(u64::try_from(total).map_err(|_| error!(ErrorCode::Overflow))?)
-}
+        accrue_twap(market, outcomes, now)?;
 
-/* ============================== TRADE INNER ============================== */
+        let idx = trigger.outcome_index as usize;
+        require!(!market.is_voided(idx), ErrorCode::OutcomeVoided);
 
-fn trade_inner(ctx: Context<Trade>, shares: u64, outcome_index: u8, is_buy: bool) -> Result<()> {
-    let trader_key = ctx.accounts.trader.key();
-    let market_key = ctx.accounts.market.key();
+        let spot = spot_price_lamports(market, outcomes, idx)?;
+        let stop_hit = trigger.stop_loss_price_lamports > 0 && spot <= trigger.stop_loss_price_lamports;
+        let take_hit = trigger.take_profit_price_lamports > 0 && spot >= trigger.take_profit_price_lamports;
+        require!(stop_hit || take_hit, ErrorCode::TriggerNotReached);
 
-    let trader_ai = ctx.accounts.trader.to_account_info();
-    let market_ai = ctx.accounts.market.to_account_info();
-    let system_ai = ctx.accounts.system_program.to_account_info();
-    let platform_ai = ctx.accounts.platform_wallet.to_account_info();
+        let shares = trigger.max_shares.min(pos.shares_of(idx));
+        require!(shares > 0, ErrorCode::NotEnoughShares);
 
-    let market = &mut ctx.accounts.market;
-    let pos = &mut ctx.accounts.user_position;
+        let discount = FeeDiscount::at(market, now);
+        let (refund, fees) = sell_to_curve(
+            market, outcomes, pos, idx, shares, &discount, 0, &market_ai, &owner_ai, &treasury_ai,
+        )?;
+        // a pushed-down spot price can fire the trigger, but not below the owner's floor
+        require!(refund >= trigger.min_refund_lamports, ErrorCode::SlippageExceeded);
+        record_candle(&ctx.accounts.price_history, market, outcomes, now, &[(idx, refund)])?;
+        record_volume(&mut ctx.accounts.trader_stats, refund, &fees, now);
+        track_volatility(market, outcomes, idx, Some(spot), now);
+        pos.last_trade_ts = now;
 
-    require!(market.status == MarketStatus::Open, ErrorCode::MarketClosed);
-    require!(!market.resolved, ErrorCode::MarketResolved);
-    require!(!market.cancelled, ErrorCode::InvalidState);
+        let tip = trigger.tip_lamports;
+        **trigger_ai.try_borrow_mut_lamports()? = trigger_ai.lamports().saturating_sub(tip);
+        **keeper_ai.try_borrow_mut_lamports()? = keeper_ai.lamports().saturating_add(tip);
 
-    let now = Clock::get()?.unix_timestamp;
-    require!(now < market.resolution_time, ErrorCode::MarketClosed);
+        emit!(TradeExecuted {
+            market: market_key,
+            user: trigger.owner,
+            is_buy: false,
+            outcome_index: trigger.outcome_index,
+            shares,
+            amount_lamports: refund,
+            platform_fee_lamports: fees.platform,
+            creator_fee_lamports: fees.creator,
+            lp_fee_lamports: fees.lp,
+            referrer_fee_lamports: fees.referrer,
+            platform_fee_bps: fees.platform_bps as u16,
+        });
 
-    require!(shares > 0, ErrorCode::InvalidShares);
-    require!(shares <= market.max_trade_shares, ErrorCode::TradeTooLarge);
+        emit!(TriggerExecuted {
+            market: market_key,
+            owner: trigger.owner,
+            trigger: trigger_key,
+            keeper: ctx.accounts.keeper.key(),
+            spot_price_lamports: spot,
+            shares,
+            amount_lamports: refund,
+            tip_lamports: tip,
+        });
 
-    if pos.market == Pubkey::default() {
-        pos.market = market_key;
-        pos.user = trader_key;
-        pos.shares = [0u64; MAX_OUTCOMES];
-        pos.claimed = false;
-        pos.last_trade_ts = 0;
-        pos.net_cost_lamports = 0;
-    } else {
-        require!(pos.market == market_key, ErrorCode::InvalidUserPosition);
-        require!(pos.user == trader_key, ErrorCode::InvalidUserPosition);
-    }
+        ctx.accounts.trigger_order.close(owner_ai)?;
 
-    if market.cooldown_seconds > 0 && pos.last_trade_ts > 0 {
-        require!(
-            now - pos.last_trade_ts >= market.cooldown_seconds,
-            ErrorCode::CooldownActive
-        );
+        Ok(())
     }
 
-    let idx = outcome_index as usize;
-    require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+    pub fn cancel_trigger_order(ctx: Context<CancelTriggerOrder>) -> Result<()> {
+        let trigger = &ctx.accounts.trigger_order;
 
-    if is_buy {
-        let start_supply = market.q[idx];
-        let cost = linear_cost_lamports(start_supply, shares)?;
-        require!(cost > 0, ErrorCode::InvalidCost);
+        emit!(TriggerOrderCancelled {
+            market: trigger.market,
+            owner: trigger.owner,
+            trigger: trigger.key(),
+        });
 
-        let platform_fee = cost.saturating_mul(PLATFORM_FEE_BPS) / 10_000;
-        let creator_fee = cost.saturating_mul(CREATOR_FEE_BPS) / 10_000;
+        Ok(())
+    }
 
-        let total_pay = cost
-            .checked_add(platform_fee).ok_or(ErrorCode::Overflow)?
-            .checked_add(creator_fee).ok_or(ErrorCode::Overflow)?;
+    /* ---------- REFERRALS ---------- */
 
-        invoke(
-            &system_instruction::transfer(&trader_key, &market_key, total_pay),
-            &[trader_ai.clone(), market_ai.clone(), system_ai],
-        )?;
+    pub fn register_referrer(ctx: Context<RegisterReferrer>, share_bps: u16) -> Result<()> {
+        require!(share_bps <= MAX_REFERRER_SHARE_BPS, ErrorCode::InvalidFeeConfig);
 
-        // platform fee still paid instantly
-        if platform_fee > 0 {
-            **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(platform_fee);
-            **platform_ai.try_borrow_mut_lamports()? = platform_ai.lamports().saturating_add(platform_fee);
-        }
+        let referrer = &mut ctx.accounts.referrer;
+        referrer.wallet = ctx.accounts.wallet.key();
+        referrer.share_bps = share_bps;
+        referrer.accrued_lamports = 0;
+        referrer.total_earned_lamports = 0;
 
-        // creator fee is escrowed in market (no instant payout)
-        if creator_fee > 0 {
-            market.creator_fee_escrow = market
-                .creator_fee_escrow
-                .checked_add(creator_fee)
-                .ok_or(ErrorCode::Overflow)?;
-        }
+        emit!(ReferrerUpdated {
+            referrer: referrer.key(),
+            wallet: referrer.wallet,
+            share_bps,
+        });
 
-        market.q[idx] = market.q[idx].checked_add(shares).ok_or(ErrorCode::Overflow)?;
-        pos.shares[idx] = pos.shares[idx].checked_add(shares).ok_or(ErrorCode::Overflow)?;
-        pos.last_trade_ts = now;
+        Ok(())
+    }
 
-        pos.net_cost_lamports = pos
-            .net_cost_lamports
-            .checked_add(cost as i128)
-            .ok_or(ErrorCode::Overflow)?;
+    pub fn set_referrer_share(ctx: Context<SetReferrerShare>, share_bps: u16) -> Result<()> {
+        require!(share_bps <= MAX_REFERRER_SHARE_BPS, ErrorCode::InvalidFeeConfig);
 
-        enforce_position_cap(market, pos, idx)?;
+        let referrer = &mut ctx.accounts.referrer;
+        referrer.share_bps = share_bps;
 
-        emit!(TradeExecuted {
-            market: market.key(),
-            user: trader_key,
-            is_buy: true,
-            outcome_index,
-            shares,
-            amount_lamports: cost,
-            platform_fee_lamports: platform_fee,
-            creator_fee_lamports: creator_fee,
+        emit!(ReferrerUpdated {
+            referrer: referrer.key(),
+            wallet: referrer.wallet,
+            share_bps,
         });
 
         Ok(())
-    } else {
-        require!(pos.shares[idx] >= shares, ErrorCode::NotEnoughShares);
-        require!(market.q[idx] >= shares, ErrorCode::InsufficientShares);
-
-        let start_supply = market.q[idx].checked_sub(shares).ok_or(ErrorCode::Overflow)?;
-        let refund = linear_cost_lamports(start_supply, shares)?;
-        require!(refund > 0, ErrorCode::InvalidCost);
+    }
 
-        let platform_fee = refund.saturating_mul(PLATFORM_FEE_BPS) / 10_000;
-        let creator_fee = refund.saturating_mul(CREATOR_FEE_BPS) / 10_000;
+    pub fn claim_referrer_fees(ctx: Context<ClaimReferrerFees>) -> Result<()> {
+        let referrer_ai = ctx.accounts.referrer.to_account_info();
+        let wallet_ai = ctx.accounts.wallet.to_account_info();
 
-        let net_receive = refund
-            .checked_sub(platform_fee).ok_or(ErrorCode::Overflow)?
-            .checked_sub(creator_fee).ok_or(ErrorCode::Overflow)?;
+        let referrer = &mut ctx.accounts.referrer;
+        let amount = referrer.accrued_lamports;
+        require!(amount > 0, ErrorCode::NothingToClaim);
 
-        require!(market_ai.lamports() >= refund, ErrorCode::InsufficientMarketBalance);
+        **referrer_ai.try_borrow_mut_lamports()? = referrer_ai.lamports().saturating_sub(amount);
+        **wallet_ai.try_borrow_mut_lamports()? = wallet_ai.lamports().saturating_add(amount);
+        referrer.accrued_lamports = 0;
 
-        **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(net_receive);
-        **trader_ai.try_borrow_mut_lamports()? = trader_ai.lamports().saturating_add(net_receive);
+        emit!(ReferrerFeesClaimed {
+            referrer: referrer.key(),
+            wallet: referrer.wallet,
+            amount_lamports: amount,
+        });
 
-        // platform fee still paid instantly
-        if platform_fee > 0 {
-            **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(platform_fee);
-            **platform_ai.try_borrow_mut_lamports()? = platform_ai.lamports().saturating_add(platform_fee);
-        }
+        Ok(())
+    }
 
-        // creator fee is escrowed in market
-        if creator_fee > 0 {
-            market.creator_fee_escrow = market
-                .creator_fee_escrow
-                .checked_add(creator_fee)
-                .ok_or(ErrorCode::Overflow)?;
-        }
+    /* ---------- TREASURY ---------- */
+
+    pub fn init_treasury(ctx: Context<InitTreasury>, authority: Pubkey) -> Result<()> {
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.authority = authority;
+        treasury.recipients = vec![TreasuryRecipient {
+            wallet: PLATFORM_WALLET,
+            bps: 10_000,
+        }];
+        treasury.total_distributed_lamports = 0;
+        treasury.insurance_bps = DEFAULT_INSURANCE_BPS;
+
+        emit!(TreasurySplitUpdated {
+            authority,
+            recipient_count: 1,
+        });
 
-        market.q[idx] = market.q[idx].checked_sub(shares).ok_or(ErrorCode::Overflow)?;
-        pos.shares[idx] = pos.shares[idx].checked_sub(shares).ok_or(ErrorCode::Overflow)?;
-        pos.last_trade_ts = now;
+        Ok(())
+    }
 
-        pos.net_cost_lamports = pos
-            .net_cost_lamports
-            .checked_sub(refund as i128)
-            .ok_or(ErrorCode::Overflow)?;
+    /// Authority-only: replaces the recipient split (bps must sum to 10_000).
+    pub fn set_treasury_split(ctx: Context<TreasuryAdmin>, recipients: Vec<TreasuryRecipient>) -> Result<()> {
+        require!(
+            !recipients.is_empty() && recipients.len() <= MAX_TREASURY_RECIPIENTS,
+            ErrorCode::InvalidTreasurySplit
+        );
 
-        if pos.net_cost_lamports < 0 {
-            pos.net_cost_lamports = 0;
+        let mut total_bps = 0u32;
+        for (i, r) in recipients.iter().enumerate() {
+            require!(r.bps > 0, ErrorCode::InvalidTreasurySplit);
+            require!(
+                recipients[..i].iter().all(|o| o.wallet != r.wallet),
+                ErrorCode::InvalidTreasurySplit
+            );
+            total_bps += r.bps as u32;
         }
+        require!(total_bps == 10_000, ErrorCode::InvalidTreasurySplit);
 
-        emit!(TradeExecuted {
-            market: market.key(),
-            user: trader_key,
-            is_buy: false,
-            outcome_index,
-            shares,
-            amount_lamports: refund,
-            platform_fee_lamports: platform_fee,
-            creator_fee_lamports: creator_fee,
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.recipients = recipients;
+
+        emit!(TreasurySplitUpdated {
+            authority: treasury.authority,
+            recipient_count: treasury.recipients.len() as u8,
         });
 
         Ok(())
     }
-}
 
-/* ============================== ANTI-MANIP ============================== */
+    pub fn set_treasury_authority(ctx: Context<TreasuryAdmin>, new_authority: Pubkey) -> Result<()> {
+        let treasury = &mut ctx.accounts.treasury;
+        treasury.authority = new_authority;
 
-fn enforce_position_cap(market: &Market, pos: &UserPosition, idx: usize) -> Result<()> {
-    let max_bps = market.max_position_bps as u64;
+        emit!(TreasurySplitUpdated {
+            authority: new_authority,
+            recipient_count: treasury.recipients.len() as u8,
+        });
 
-    if max_bps >= 10_000 {
-        return Ok(());
+        Ok(())
     }
 
-    let mut total: u128 = 0;
-    for i in 0..(market.outcome_count as usize) {
-        total = total
-            .checked_add(market.q[i] as u128)
-            .ok_or(ErrorCode::Overflow)?;
+    /// Authority-only: share of each distribution that goes to the insurance fund first.
+    pub fn set_insurance_share(ctx: Context<TreasuryAdmin>, insurance_bps: u16) -> Result<()> {
+        require!(insurance_bps <= MAX_INSURANCE_BPS, ErrorCode::InvalidTreasurySplit);
+        ctx.accounts.treasury.insurance_bps = insurance_bps;
+        Ok(())
     }
 
-    if total < market.max_trade_shares as u128 {
-        return Ok(());
-    }
+    /// Permissionless: pays the treasury balance above rent to the insurance fund
+    /// (insurance_bps) and then the configured recipients.
+    /// remaining_accounts: recipient wallets, in split order.
+    pub fn distribute_treasury<'info>(ctx: Context<'_, '_, 'info, 'info, DistributeTreasury<'info>>) -> Result<()> {
+        let treasury_ai = ctx.accounts.treasury.to_account_info();
+        let fund_ai = ctx.accounts.insurance_fund.to_account_info();
+        let treasury = &mut ctx.accounts.treasury;
+        let fund = &mut ctx.accounts.insurance_fund;
 
-    let user: u128 = pos.shares[idx] as u128;
+        require!(
+            ctx.remaining_accounts.len() == treasury.recipients.len(),
+            ErrorCode::InvalidTreasurySplit
+        );
 
-    let max_allowed: u128 = total
-        .checked_mul(max_bps as u128)
-        .ok_or(ErrorCode::Overflow)?
-        .checked_div(10_000u128)
-        .ok_or(ErrorCode::Overflow)?;
+        let rent_floor = Rent::get()?.minimum_balance(treasury_ai.data_len());
+        let mut available = treasury_ai.lamports().saturating_sub(rent_floor);
+        require!(available > 0, ErrorCode::NothingToClaim);
 
-    require!(user <= max_allowed, ErrorCode::PositionCapExceeded);
-    Ok(())
-}
+        let insurance = ((available as u128 * treasury.insurance_bps as u128) / 10_000) as u64;
+        if insurance > 0 {
+            **treasury_ai.try_borrow_mut_lamports()? = treasury_ai.lamports().saturating_sub(insurance);
+            **fund_ai.try_borrow_mut_lamports()? = fund_ai.lamports().saturating_add(insurance);
+            fund.total_contributed_lamports = fund.total_contributed_lamports.saturating_add(insurance);
+            available -= insurance;
+        }
 
-/* ============================== ACCOUNTS ============================== */
+        let mut distributed = 0u64;
+        for (r, wallet_ai) in treasury.recipients.iter().zip(ctx.remaining_accounts.iter()) {
+            require_keys_eq!(wallet_ai.key(), r.wallet, ErrorCode::InvalidTreasurySplit);
+
+            let share = ((available as u128 * r.bps as u128) / 10_000) as u64;
+            if share == 0 {
+                continue;
+            }
+            **treasury_ai.try_borrow_mut_lamports()? = treasury_ai.lamports().saturating_sub(share);
+            **wallet_ai.try_borrow_mut_lamports()? = wallet_ai.lamports().saturating_add(share);
+            distributed = distributed.saturating_add(share);
+        }
 
-#[derive(Accounts)]
-pub struct CreateMarket<'info> {
-    #[account(init, payer = creator, space = Market::SPACE)]
-    pub market: Account<'info, Market>,
+        treasury.total_distributed_lamports = treasury.total_distributed_lamports.saturating_add(distributed);
 
-    #[account(mut)]
-    pub creator: Signer<'info>,
+        emit!(TreasuryDistributed {
+            amount_lamports: distributed,
+            insurance_lamports: insurance,
+            recipient_count: treasury.recipients.len() as u8,
+        });
 
-    pub system_program: Program<'info, System>,
-}
+        Ok(())
+    }
+
+    pub fn init_insurance_fund(ctx: Context<InitInsuranceFund>) -> Result<()> {
+        let fund = &mut ctx.accounts.insurance_fund;
+        fund.total_contributed_lamports = 0;
+        fund.total_drawn_lamports = 0;
+        Ok(())
+    }
+
+    /* ---------- FEE TIERS ---------- */
+
+    pub fn init_protocol_config(ctx: Context<InitProtocolConfig>) -> Result<()> {
+        let config = &mut ctx.accounts.protocol_config;
+        config.tiers = Vec::new();
+        config.maker_rebate_bps = 0;
+        Ok(())
+    }
+
+    /// Replaces the volume tier table. Tiers ascend by volume and never raise the fee.
+    pub fn set_fee_tiers(ctx: Context<SetProtocolConfig>, tiers: Vec<FeeTier>, maker_rebate_bps: u16) -> Result<()> {
+        require!(tiers.len() <= MAX_FEE_TIERS, ErrorCode::InvalidFeeConfig);
+        require!(maker_rebate_bps as u64 <= PLATFORM_FEE_BPS, ErrorCode::InvalidFeeConfig);
+
+        let mut prev_volume = 0u64;
+        let mut prev_bps = PLATFORM_FEE_BPS as u16;
+        for (i, tier) in tiers.iter().enumerate() {
+            require!(i == 0 || tier.min_volume_lamports > prev_volume, ErrorCode::InvalidFeeConfig);
+            require!(tier.platform_fee_bps <= prev_bps, ErrorCode::InvalidFeeConfig);
+            prev_volume = tier.min_volume_lamports;
+            prev_bps = tier.platform_fee_bps;
+        }
+
+        let config = &mut ctx.accounts.protocol_config;
+        config.tiers = tiers;
+        config.maker_rebate_bps = maker_rebate_bps;
+
+        emit!(FeeTiersUpdated {
+            tier_count: config.tiers.len() as u8,
+            maker_rebate_bps,
+        });
+
+        Ok(())
+    }
+
+    pub fn init_trader_stats(ctx: Context<InitTraderStats>) -> Result<()> {
+        let stats = &mut ctx.accounts.trader_stats;
+        stats.trader = ctx.accounts.trader.key();
+        stats.last_day = Clock::get()?.unix_timestamp / 86_400;
+        stats.day_volumes = [0; VOLUME_WINDOW_DAYS];
+        stats.market_maker = false;
+        stats.lifetime_volume_lamports = 0;
+        stats.rebates_lamports = 0;
+        Ok(())
+    }
+
+    /// Admin designates (or revokes) a market-maker wallet for maker rebates.
+    pub fn set_market_maker(ctx: Context<SetMarketMaker>, market_maker: bool) -> Result<()> {
+        let stats = &mut ctx.accounts.trader_stats;
+        stats.market_maker = market_maker;
+
+        emit!(MarketMakerUpdated {
+            trader: stats.trader,
+            market_maker,
+        });
+
+        Ok(())
+    }
+
+    /* ---------- PRICE HISTORY ---------- */
+
+    /// Opt-in candle buffer for a market; anyone may pay for it.
+    pub fn init_price_history(ctx: Context<InitPriceHistory>, interval_seconds: i64) -> Result<()> {
+        require!(
+            (MIN_CANDLE_SECONDS..=MAX_CANDLE_SECONDS).contains(&interval_seconds),
+            ErrorCode::InvalidCandleInterval
+        );
+        require!(
+            ctx.accounts.market.outcome_count as usize <= PRICE_HISTORY_MAX_OUTCOMES,
+            ErrorCode::TooManyOutcomes
+        );
+
+        let mut history = ctx.accounts.price_history.load_init()?;
+        history.market = ctx.accounts.market.key();
+        history.interval_seconds = interval_seconds;
+
+        emit!(PriceHistoryInitialized {
+            market: history.market,
+            interval_seconds,
+            by: ctx.accounts.payer.key(),
+        });
+
+        Ok(())
+    }
+
+    /* ---------- TWAP ORACLE ---------- */
+
+    /// Current cumulative price checkpoint for an outcome (returned via return data).
+    pub fn observe_price(ctx: Context<ObservePrice>, outcome_index: u8) -> Result<PriceObservation> {
+        let market = &ctx.accounts.market;
+        let idx = outcome_index as usize;
+        require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+
+        let now = Clock::get()?.unix_timestamp;
+        Ok(PriceObservation {
+            price_cumulative: observe_cumulative(market, &ctx.accounts.market_outcomes, idx, now),
+            timestamp: now,
+        })
+    }
+
+    /// Time-weighted average spot price (lamports per share) between an earlier
+    /// checkpoint from `observe_price` and now.
+    pub fn get_twap(ctx: Context<ObservePrice>, outcome_index: u8, since: PriceObservation) -> Result<u64> {
+        let market = &ctx.accounts.market;
+        let idx = outcome_index as usize;
+        require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(since.timestamp < now, ErrorCode::InvalidObservation);
+
+        let cumulative = observe_cumulative(market, &ctx.accounts.market_outcomes, idx, now);
+        let twap = cumulative.wrapping_sub(since.price_cumulative) / (now - since.timestamp) as u128;
+        u64::try_from(twap).map_err(|_| error!(ErrorCode::InvalidObservation))
+    }
+
+    /* ---------- COMMIT-REVEAL ---------- */
+
+    /// Posts hash(outcome_index, shares, max_pay_lamports, salt) with a deposit
+    /// that must cover max_pay. Reveal from the next slot until the timeout.
+    pub fn commit_trade(ctx: Context<CommitTrade>, commitment: [u8; 32], deposit_lamports: u64) -> Result<()> {
+        let trader_key = ctx.accounts.trader.key();
+        let commit_key = ctx.accounts.trade_commit.key();
+
+        let trader_ai = ctx.accounts.trader.to_account_info();
+        let commit_ai = ctx.accounts.trade_commit.to_account_info();
+        let system_ai = ctx.accounts.system_program.to_account_info();
+
+        let market = &ctx.accounts.market;
+        let commit = &mut ctx.accounts.trade_commit;
+
+        require_market_tradable(market)?;
+        require!(market.commit_reveal, ErrorCode::InvalidState);
+        require!(deposit_lamports > 0, ErrorCode::InvalidCost);
+
+        invoke(
+            &system_instruction::transfer(&trader_key, &commit_key, deposit_lamports),
+            &[trader_ai, commit_ai, system_ai],
+        )?;
+
+        commit.market = market.key();
+        commit.trader = trader_key;
+        commit.commitment = commitment;
+        commit.deposit_lamports = deposit_lamports;
+        commit.slot = Clock::get()?.slot;
+
+        emit!(TradeCommitted {
+            market: market.key(),
+            trader: trader_key,
+            commitment,
+            deposit_lamports,
+            slot: commit.slot,
+        });
+
+        Ok(())
+    }
+
+    pub fn reveal_trade(
+        ctx: Context<RevealTrade>,
+        outcome_index: u8,
+        shares: u64,
+        max_pay_lamports: u64,
+        salt: [u8; 32],
+    ) -> Result<()> {
+        let trader_ai = ctx.accounts.trade.trader.to_account_info();
+        let commit = &ctx.accounts.trade_commit;
+
+        let slot = Clock::get()?.slot;
+        require!(slot > commit.slot, ErrorCode::RevealTooEarly);
+        require!(
+            slot <= commit.slot.saturating_add(COMMIT_REVEAL_TIMEOUT_SLOTS),
+            ErrorCode::RevealExpired
+        );
+
+        let hash = hashv(&[
+            &[outcome_index][..],
+            &shares.to_le_bytes(),
+            &max_pay_lamports.to_le_bytes(),
+            &salt,
+        ]);
+        require!(hash.to_bytes() == commit.commitment, ErrorCode::CommitmentMismatch);
+        require!(max_pay_lamports <= commit.deposit_lamports, ErrorCode::InsufficientDeposit);
+
+        let deposit = commit.deposit_lamports;
+
+        // deposit (and rent) back to the trader, who then pays through the curve
+        ctx.accounts.trade_commit.close(trader_ai)?;
+
+        trade_inner(&mut ctx.accounts.trade, shares, outcome_index, true, max_pay_lamports)?;
+
+        emit!(TradeRevealed {
+            market: ctx.accounts.trade.market.key(),
+            trader: ctx.accounts.trade.trader.key(),
+            outcome_index,
+            shares,
+            deposit_lamports: deposit,
+        });
+
+        Ok(())
+    }
+
+    /// Unrevealed commit after the timeout: deposit back minus COMMIT_PENALTY_BPS
+    /// (waived once the market stops trading).
+    pub fn refund_commit(ctx: Context<RefundCommit>) -> Result<()> {
+        let trader_ai = ctx.accounts.trader.to_account_info();
+        let commit_ai = ctx.accounts.trade_commit.to_account_info();
+        let treasury_ai = ctx.accounts.treasury.to_account_info();
+
+        let market = &ctx.accounts.market;
+        let commit = &ctx.accounts.trade_commit;
+
+        let tradable = require_market_tradable(market).is_ok();
+        let slot = Clock::get()?.slot;
+        require!(
+            !tradable || slot > commit.slot.saturating_add(COMMIT_REVEAL_TIMEOUT_SLOTS),
+            ErrorCode::CommitStillActive
+        );
+
+        let penalty = if tradable {
+            commit.deposit_lamports.saturating_mul(COMMIT_PENALTY_BPS) / 10_000
+        } else {
+            0
+        };
+
+        if penalty > 0 {
+            **commit_ai.try_borrow_mut_lamports()? = commit_ai.lamports().saturating_sub(penalty);
+            **treasury_ai.try_borrow_mut_lamports()? = treasury_ai.lamports().saturating_add(penalty);
+        }
+
+        emit!(CommitRefunded {
+            market: market.key(),
+            trader: ctx.accounts.trader.key(),
+            refund_lamports: commit.deposit_lamports - penalty,
+            penalty_lamports: penalty,
+        });
+
+        ctx.accounts.trade_commit.close(trader_ai)?;
+
+        Ok(())
+    }
+
+    /* ---------- BATCH AUCTION ---------- */
+
+    pub fn submit_batch_order(
+        ctx: Context<SubmitBatchOrder>,
+        outcome_index: u8,
+        is_buy: bool,
+        shares: u64,
+        limit_price_lamports: u64, // per share: max for buys, min for sells
+    ) -> Result<()> {
+        let trader_key = ctx.accounts.trader.key();
+        let market_key = ctx.accounts.market.key();
+        let queue_key = ctx.accounts.order_queue.key();
+
+        let trader_ai = ctx.accounts.trader.to_account_info();
+        let queue_ai = ctx.accounts.order_queue.to_account_info();
+        let pos_ai = ctx.accounts.user_position.to_account_info();
+        let system_ai = ctx.accounts.system_program.to_account_info();
+
+        let market = &ctx.accounts.market;
+        let queue = &mut ctx.accounts.order_queue;
+        let pos = &mut ctx.accounts.user_position;
+
+        let now = require_market_tradable(market)?;
+        require!(market.batch_seconds > 0, ErrorCode::InvalidState);
+
+        let idx = outcome_index as usize;
+        require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+        require!(!market.is_voided(idx), ErrorCode::OutcomeVoided);
+        require!(shares > 0, ErrorCode::InvalidShares);
+        require!(shares <= market.max_trade_shares, ErrorCode::TradeTooLarge);
+
+        init_or_check_position(pos, market_key, trader_key)?;
+
+        if queue.market == Pubkey::default() {
+            queue.market = market_key;
+        }
+        require_keys_eq!(queue.market, market_key, ErrorCode::InvalidState);
+
+        // the first order after a clear opens the next batch
+        if queue.orders.is_empty() {
+            queue.batch_end_ts = now
+                .checked_add(market.batch_seconds)
+                .ok_or(ErrorCode::Overflow)?;
+        }
+        require!(now < queue.batch_end_ts, ErrorCode::BatchPendingClear);
+        require!(queue.orders.len() < MAX_QUEUED_ORDERS, ErrorCode::BatchFull);
+        require!(
+            queue.orders.iter().filter(|o| o.trader == trader_key).count() < MAX_QUEUED_ORDERS_PER_TRADER,
+            ErrorCode::TooManyQueuedOrders
+        );
+
+        let (escrow_lamports, escrow_cost_lamports) = if is_buy {
+            require!(limit_price_lamports > 0, ErrorCode::InvalidCost);
+
+            // worst case: every share at the limit, plus fees on it
+            let notional = shares
+                .checked_mul(limit_price_lamports)
+                .ok_or(ErrorCode::Overflow)?;
+            let escrow = notional
+                .checked_add(trade_fees_with(market, notional, &FeeDiscount::worst_case(market)).total())
+                .ok_or(ErrorCode::Overflow)?;
+
+            invoke(
+                &system_instruction::transfer(&trader_key, &queue_key, escrow),
+                &[trader_ai.clone(), queue_ai, system_ai.clone()],
+            )?;
+            (escrow, 0)
+        } else {
+            // sells lock their shares (and cost basis) until the batch clears
+            (0, escrow_shares_out(pos, idx, shares)?)
+        };
+
+        queue.orders.push(QueuedOrder {
+            trader: trader_key,
+            outcome_index,
+            is_buy,
+            shares,
+            limit_price_lamports,
+            escrow_lamports,
+            escrow_cost_lamports,
+        });
+
+        // the crank can't pay for growth, so reserve room for entries that buys create
+        // and escrowed sells bring back
+        let mut new_outcomes: Vec<u8> = Vec::new();
+        for o in queue.orders.iter().filter(|o| o.trader == trader_key) {
+            if pos.entry(o.outcome_index as usize).is_none() && !new_outcomes.contains(&o.outcome_index) {
+                new_outcomes.push(o.outcome_index);
+            }
+        }
+        let needed = 8 + pos.try_to_vec()?.len() + new_outcomes.len() * PositionEntry::SPACE;
+        grow_account(&pos_ai, &trader_ai, &system_ai, needed)?;
+
+        emit!(BatchOrderQueued {
+            market: market_key,
+            trader: trader_key,
+            batch_id: queue.batch_id,
+            outcome_index,
+            is_buy,
+            shares,
+            limit_price_lamports,
+        });
+
+        Ok(())
+    }
+
+    /// Permissionless crank. remaining_accounts: (user_position, trader wallet) per
+    /// distinct trader, in order of first appearance in the queue, then optionally
+    /// any of those traders' TraderStats to credit their volume.
+    pub fn clear_batch<'info>(ctx: Context<'_, '_, 'info, 'info, ClearBatch<'info>>) -> Result<()> {
+        clear_batch_inner(ctx)
+    }
+
+    /* ---------- GRADUATE (permissionless) ---------- */
+
+    /// Converts a linear market that crossed its volume or supply threshold into an
+    /// LMSR pool seeded at the curve's implied probabilities. Collateral backs the
+    /// outstanding shares at a fixed unit; the rest becomes the (locked) subsidy.
+    pub fn graduate(ctx: Context<Graduate>) -> Result<()> {
+        let market_ai = ctx.accounts.market.to_account_info();
+
+        let market = &mut ctx.accounts.market;
+        let outcomes = &mut ctx.accounts.market_outcomes;
+
+        let now = require_market_tradable(market)?;
+        require!(market.pricing_model == PRICING_LINEAR, ErrorCode::InvalidPricingModel);
+        require!(!market.graduated, ErrorCode::AlreadyGraduated);
+
+        // close out the linear-curve price before the model switches
+        accrue_twap(market, outcomes, now)?;
+        require!(market.voided_outcomes == 0, ErrorCode::OutcomeVoided);
+
+        let total_supply = outcomes
+            .q
+            .iter()
+            .try_fold(0u64, |acc, q| acc.checked_add(*q))
+            .ok_or(ErrorCode::Overflow)?;
+
+        let by_volume = market.graduation_volume_lamports > 0
+            && market.volume_lamports >= market.graduation_volume_lamports;
+        let by_supply = market.graduation_supply > 0 && total_supply >= market.graduation_supply;
+        require!(by_volume || by_supply, ErrorCode::GraduationThresholdNotMet);
+
+        // withheld platform fee becomes the creator bonus
+        let bonus = market.graduation_bonus_lamports;
+        market.creator_fee_escrow = market
+            .creator_fee_escrow
+            .checked_add(bonus)
+            .ok_or(ErrorCode::Overflow)?;
+        market.graduation_bonus_lamports = 0;
+
+        let rent = Rent::get()?.minimum_balance(market_ai.data_len());
+        let collateral = market_ai
+            .lamports()
+            .saturating_sub(rent)
+            .saturating_sub(market.creator_fee_escrow)
+            .saturating_sub(market.void_reserve_lamports);
+
+        let (unit, b, reserves) = lmsr_graduation_seed(market, outcomes, collateral)?;
+        let subsidy = collateral
+            .checked_sub(graduation_backing(market, outcomes, unit)?)
+            .ok_or(ErrorCode::Overflow)?;
+
+        market.pricing_model = PRICING_LMSR;
+        market.set_price_lamports = unit;
+        market.b_lamports = b;
+        market.subsidy_lamports = subsidy;
+        market.graduated = true;
+        outcomes.reserves = reserves;
+
+        emit!(MarketGraduated {
+            market: market.key(),
+            by: ctx.accounts.caller.key(),
+            volume_lamports: market.volume_lamports,
+            total_supply,
+            set_price_lamports: unit,
+            b_lamports: b,
+            subsidy_lamports: subsidy,
+            creator_bonus_lamports: bonus,
+        });
+
+        Ok(())
+    }
+
+    /* ---------- PROPOSE (creator) ---------- */
+
+    pub fn propose_resolution(ctx: Context<ProposeResolution>, proposed_outcome: u8) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+
+        require!(market.status == MarketStatus::Open, ErrorCode::InvalidState);
+        require!(!market.cancelled, ErrorCode::InvalidState);
+        require!(!market.resolved, ErrorCode::MarketResolved);
+
+        require!(now >= market.resolution_time, ErrorCode::MarketNotEnded);
+
+        // 24h window to propose after end
+        let cutoff = market
+            .resolution_time
+            .checked_add(CREATOR_PROPOSE_WINDOW)
+            .ok_or(ErrorCode::Overflow)?;
+        require!(now <= cutoff, ErrorCode::TooLateToPropose);
+
+        let idx = proposed_outcome as usize;
+        require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+        require!(!market.is_voided(idx), ErrorCode::OutcomeVoided);
+
+        market.status = MarketStatus::Proposed;
+        market.proposed_outcome = Some(proposed_outcome);
+        market.proposed_at = Some(now);
+        market.contest_deadline = Some(now.checked_add(DISPUTE_WINDOW).ok_or(ErrorCode::Overflow)?);
+        market.dispute_count = 0;
+
+        emit!(ResolutionProposed {
+            market: market.key(),
+            proposed_outcome,
+            proposed_at: now,
+            contest_deadline: market.contest_deadline.unwrap(),
+        });
+
+        Ok(())
+    }
+
+    /* ---------- DISPUTE (any user during contest window) ---------- */
+
+    pub fn dispute(ctx: Context<Dispute>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+
+        require!(market.status == MarketStatus::Proposed, ErrorCode::InvalidState);
+        require!(!market.cancelled, ErrorCode::InvalidState);
+        require!(!market.resolved, ErrorCode::MarketResolved);
+
+        let deadline = market.contest_deadline.ok_or(ErrorCode::InvalidState)?;
+        require!(now < deadline, ErrorCode::DisputeWindowClosed);
+
+        market.dispute_count = market.dispute_count.saturating_add(1);
+
+        emit!(Disputed {
+            market: market.key(),
+            by: ctx.accounts.user.key(),
+            dispute_count: market.dispute_count,
+        });
+
+        Ok(())
+    }
+
+    /* ---------- ADMIN FINALIZE (0 disputes) ---------- */
+
+    pub fn admin_finalize_no_disputes(ctx: Context<AdminFinalize>) -> Result<()> {
+        let market_ai = ctx.accounts.market.to_account_info();
+        let treasury_ai = ctx.accounts.treasury.to_account_info();
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+
+        require_keys_eq!(ctx.accounts.admin.key(), ADMIN_AUTHORITY, ErrorCode::Unauthorized);
+
+        require!(market.status == MarketStatus::Proposed, ErrorCode::InvalidState);
+        require!(!market.resolved, ErrorCode::MarketResolved);
+        require!(!market.cancelled, ErrorCode::InvalidState);
+
+        let deadline = market.contest_deadline.ok_or(ErrorCode::InvalidState)?;
+        require!(now >= deadline, ErrorCode::TooEarly);
+
+        require!(market.dispute_count == 0, ErrorCode::HasDisputes);
+
+        let out = market.proposed_outcome.ok_or(ErrorCode::InvalidState)?;
+
+        market.status = MarketStatus::Finalized;
+        market.resolved = true;
+        market.winning_outcome = Some(out);
+        snapshot_winning_pool(market, &market_ai, &ctx.accounts.market_outcomes, out as usize)?;
+        sweep_graduation_residual(market, &market_ai, &treasury_ai, &ctx.accounts.market_outcomes)?;
+
+        emit!(Finalized {
+            market: market.key(),
+            winning_outcome: out,
+            by: ctx.accounts.admin.key(),
+        });
+
+        Ok(())
+    }
+
+    /* ---------- ADMIN FINALIZE (with disputes) ---------- */
+
+    pub fn admin_finalize(ctx: Context<AdminFinalize>, winning_outcome: u8) -> Result<()> {
+        let market_ai = ctx.accounts.market.to_account_info();
+        let treasury_ai = ctx.accounts.treasury.to_account_info();
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+
+        require_keys_eq!(ctx.accounts.admin.key(), ADMIN_AUTHORITY, ErrorCode::Unauthorized);
+
+        require!(market.status == MarketStatus::Proposed, ErrorCode::InvalidState);
+        require!(!market.resolved, ErrorCode::MarketResolved);
+        require!(!market.cancelled, ErrorCode::InvalidState);
+
+        let deadline = market.contest_deadline.ok_or(ErrorCode::InvalidState)?;
+        require!(now >= deadline, ErrorCode::TooEarly);
+
+        require!(market.dispute_count > 0, ErrorCode::NoDispute);
+
+        let idx = winning_outcome as usize;
+        require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+        require!(!market.is_voided(idx), ErrorCode::OutcomeVoided);
+
+        market.status = MarketStatus::Finalized;
+        market.resolved = true;
+        market.winning_outcome = Some(winning_outcome);
+        snapshot_winning_pool(market, &market_ai, &ctx.accounts.market_outcomes, idx)?;
+        sweep_graduation_residual(market, &market_ai, &treasury_ai, &ctx.accounts.market_outcomes)?;
+
+        emit!(Finalized {
+            market: market.key(),
+            winning_outcome,
+            by: ctx.accounts.admin.key(),
+        });
+
+        Ok(())
+    }
+
+    /* ---------- ADMIN CANCEL (with disputes) ---------- */
+
+    pub fn admin_cancel(ctx: Context<AdminResolve>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+
+        require_keys_eq!(ctx.accounts.admin.key(), ADMIN_AUTHORITY, ErrorCode::Unauthorized);
+
+        require!(market.status == MarketStatus::Proposed, ErrorCode::InvalidState);
+        require!(!market.resolved, ErrorCode::MarketResolved);
+        require!(!market.cancelled, ErrorCode::InvalidState);
+
+        let deadline = market.contest_deadline.ok_or(ErrorCode::InvalidState)?;
+        require!(now >= deadline, ErrorCode::TooEarly);
+
+        require!(market.dispute_count > 0, ErrorCode::NoDispute);
+
+        market.status = MarketStatus::Cancelled;
+        market.cancelled = true;
+
+        emit!(Cancelled {
+            market: market.key(),
+            by: ctx.accounts.admin.key(),
+            reason: CancelReason::Admin,
+        });
+
+        Ok(())
+    }
+
+    /* ---------- ADMIN CANCEL (no proposal after 24h) ---------- */
+
+    pub fn admin_cancel_no_proposal(ctx: Context<AdminResolve>) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let now = Clock::get()?.unix_timestamp;
+
+        require_keys_eq!(ctx.accounts.admin.key(), ADMIN_AUTHORITY, ErrorCode::Unauthorized);
+
+        require!(market.status == MarketStatus::Open, ErrorCode::InvalidState);
+        require!(!market.resolved, ErrorCode::MarketResolved);
+        require!(!market.cancelled, ErrorCode::InvalidState);
+
+        require!(now >= market.resolution_time, ErrorCode::MarketNotEnded);
+
+        let cutoff = market
+            .resolution_time
+            .checked_add(CREATOR_PROPOSE_WINDOW)
+            .ok_or(ErrorCode::Overflow)?;
+        require!(now >= cutoff, ErrorCode::TooEarly);
+
+        market.status = MarketStatus::Cancelled;
+        market.cancelled = true;
+
+        emit!(Cancelled {
+            market: market.key(),
+            by: ctx.accounts.admin.key(),
+            reason: CancelReason::NoProposal24h,
+        });
+
+        Ok(())
+    }
+
+    /* ---------- ADMIN VOID OUTCOME (multi only) ---------- */
+
+    pub fn admin_void_outcome(ctx: Context<AdminVoidOutcome>, outcome_index: u8) -> Result<()> {
+        let market = &mut ctx.accounts.market;
+        let outcomes = &ctx.accounts.market_outcomes;
+
+        require_keys_eq!(ctx.accounts.admin.key(), ADMIN_AUTHORITY, ErrorCode::Unauthorized);
+
+        require!(market.market_type == 1, ErrorCode::InvalidState);
+        require!(market.status == MarketStatus::Open, ErrorCode::InvalidState);
+        require!(!market.resolved, ErrorCode::MarketResolved);
+        require!(!market.cancelled, ErrorCode::InvalidState);
+
+        let idx = outcome_index as usize;
+        require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+        require!(!market.is_voided(idx), ErrorCode::OutcomeVoided);
+
+        // at least 2 live outcomes must remain resolvable
+        let live = (0..market.outcome_count as usize)
+            .filter(|&i| i != idx && !market.is_voided(i))
+            .count();
+        require!(live >= 2, ErrorCode::InvalidOutcomes);
+
+        market.voided_outcomes |= 1u64 << idx;
+
+        // ring-fence the outcome's cost basis so winners can't drain it
+        let reserve = outcomes.outcome_cost[idx];
+        market.void_reserve_lamports = market
+            .void_reserve_lamports
+            .checked_add(reserve)
+            .ok_or(ErrorCode::Overflow)?;
+
+        emit!(OutcomeVoided {
+            market: market.key(),
+            outcome_index,
+            by: ctx.accounts.admin.key(),
+            reserved_lamports: reserve,
+        });
+
+        Ok(())
+    }
+
+    /* ---------- ADD OUTCOME (creator/admin, multi only) ---------- */
+
+    pub fn add_outcome(ctx: Context<AddOutcome>, outcome_name: String) -> Result<()> {
+        let outcomes_ai = ctx.accounts.market_outcomes.to_account_info();
+        let authority_ai = ctx.accounts.authority.to_account_info();
+        let system_ai = ctx.accounts.system_program.to_account_info();
+
+        let market = &mut ctx.accounts.market;
+        let outcomes = &mut ctx.accounts.market_outcomes;
+        let authority = ctx.accounts.authority.key();
+
+        require!(
+            authority == market.creator || authority == ADMIN_AUTHORITY,
+            ErrorCode::Unauthorized
+        );
+
+        require!(market.market_type == 1, ErrorCode::InvalidState);
+        require!(market.status == MarketStatus::Open, ErrorCode::InvalidState);
+        require!(!market.resolved, ErrorCode::MarketResolved);
+        require!(!market.cancelled, ErrorCode::InvalidState);
+
+        // an fpmm pool's invariant spans all outcomes; it can't take a new one
+        require!(market.pricing_model == PRICING_LINEAR, ErrorCode::InvalidPricingModel);
+        // outstanding complete sets were priced for the old outcome count
+        require!(market.complete_sets == 0, ErrorCode::CompleteSetsOutstanding);
+
+        let now = Clock::get()?.unix_timestamp;
+        require!(now < market.resolution_time, ErrorCode::MarketClosed);
+
+        require!((market.outcome_count as usize) < MAX_OUTCOMES, ErrorCode::TooManyOutcomes);
+        // a charted market keeps enough candles per outcome
+        if !ctx.accounts.price_history.data_is_empty() {
+            require!(
+                (market.outcome_count as usize) < PRICE_HISTORY_MAX_OUTCOMES,
+                ErrorCode::TooManyOutcomes
+            );
+        }
+
+        let s = outcome_name.trim();
+        require!(!s.is_empty(), ErrorCode::InvalidOutcomes);
+        require!(s.len() <= MAX_NAME_LEN, ErrorCode::InvalidOutcomes);
+
+        accrue_twap(market, outcomes, now)?;
+
+        let idx = market.outcome_count as usize;
+        outcomes.push_outcome(outcome_name.clone());
+        market.voided_outcomes &= !(1u64 << idx);
+        market.outcome_count = market.outcome_count.checked_add(1).ok_or(ErrorCode::Overflow)?;
+        market.set_price_lamports = market
+            .base_price_lamports
+            .checked_mul(market.outcome_count as u64)
+            .ok_or(ErrorCode::Overflow)?;
+
+        // outcomes account is sized to fit; grow it for the new entry
+        let needed = 8 + outcomes.try_to_vec()?.len();
+        grow_account(&outcomes_ai, &authority_ai, &system_ai, needed)?;
+
+        emit!(OutcomeAdded {
+            market: market.key(),
+            outcome_index: idx as u8,
+            outcome_name,
+            outcome_count: market.outcome_count,
+            by: authority,
+        });
+
+        Ok(())
+    }
+
+    /* ---------- CLAIM CREATOR FEES (escrow) ---------- */
+
+    pub fn claim_creator_fees(ctx: Context<ClaimCreatorFees>) -> Result<()> {
+        let market_ai = ctx.accounts.market.to_account_info();
+        let creator_ai = ctx.accounts.creator.to_account_info();
+
+        let market = &mut ctx.accounts.market;
+
+        // only creator
+        require_keys_eq!(ctx.accounts.creator.key(), market.creator, ErrorCode::Unauthorized);
+
+        // only if finalized (never on cancelled)
+        require!(market.status == MarketStatus::Finalized, ErrorCode::InvalidState);
+        require!(market.resolved, ErrorCode::MarketNotResolved);
+        require!(!market.cancelled, ErrorCode::InvalidState);
+
+        // never graduated: the withheld bonus goes back to the treasury
+        let unearned = market.graduation_bonus_lamports;
+        if unearned > 0 {
+            let treasury_ai = ctx.accounts.treasury.to_account_info();
+            require!(market_ai.lamports() >= unearned, ErrorCode::InsufficientMarketBalance);
+            **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(unearned);
+            **treasury_ai.try_borrow_mut_lamports()? = treasury_ai.lamports().saturating_add(unearned);
+            market.graduation_bonus_lamports = 0;
+        }
+
+        let amount = market.creator_fee_escrow;
+        require!(amount > 0 || unearned > 0, ErrorCode::NothingToClaim);
+        require!(market_ai.lamports() >= amount, ErrorCode::InsufficientMarketBalance);
+
+        **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(amount);
+        **creator_ai.try_borrow_mut_lamports()? = creator_ai.lamports().saturating_add(amount);
+
+        market.creator_fee_escrow = 0;
+
+        emit!(CreatorFeesClaimed {
+            market: market.key(),
+            creator: ctx.accounts.creator.key(),
+            amount_lamports: amount,
+        });
+
+        Ok(())
+    }
+
+    /* ---------- CLAIM WINNINGS ---------- */
+
+    pub fn claim_winnings(ctx: Context<ClaimWinnings>) -> Result<()> {
+        let market_ai = ctx.accounts.market.to_account_info();
+        let user_ai = ctx.accounts.user.to_account_info();
+        let treasury_ai = ctx.accounts.treasury.to_account_info();
+
+        let market = &mut ctx.accounts.market;
+        let pos = &mut ctx.accounts.user_position;
+
+        require!(market.resolved, ErrorCode::MarketNotResolved);
+        require!(market.status == MarketStatus::Finalized, ErrorCode::InvalidState);
+        require!(!market.cancelled, ErrorCode::InvalidState);
+
+        require!(!pos.claimed, ErrorCode::AlreadyClaimed);
+
+        require!(pos.market == market.key(), ErrorCode::InvalidUserPosition);
+        require!(pos.user == ctx.accounts.user.key(), ErrorCode::InvalidUserPosition);
+
+        let winning = market.winning_outcome.ok_or(ErrorCode::MarketNotResolved)? as usize;
+
+        let user_shares = pos.shares_of(winning);
+        require!(user_shares > 0, ErrorCode::NoWinningShares);
+
+        let payout = if market.uses_subsidy() {
+            // lmsr shares pay a fixed unit; the residual belongs to subsidy providers
+            market.claimed_winning_shares = market
+                .claimed_winning_shares
+                .checked_add(user_shares)
+                .ok_or(ErrorCode::Overflow)?;
+            user_shares
+                .checked_mul(market.set_price_lamports)
+                .ok_or(ErrorCode::Overflow)?
+        } else {
+            // pro-rata share of the pool as it stood at finalization
+            pro_rata_payout(user_shares, market.winning_pool_lamports, market.winning_supply)?
+        };
+
+        require!(payout > 0, ErrorCode::InvalidPayout);
+
+        // the insurance fund covers whatever the market is short of this claim
+        cover_shortfall(
+            &mut ctx.accounts.insurance_fund,
+            market,
+            &market_ai,
+            payout,
+            ctx.accounts.user.key(),
+            ClaimKind::Winnings,
+        )?;
+
+        // settlement mode: platform share leaves now, creator share joins the escrow
+        let (platform_fee, creator_fee) = if market.fee_mode == FEE_MODE_SETTLEMENT {
+            (
+                payout.saturating_mul(PLATFORM_FEE_BPS) / 10_000,
+                payout.saturating_mul(market.creator_fee_bps as u64) / 10_000,
+            )
+        } else {
+            (0, 0)
+        };
+        let net_payout = payout
+            .checked_sub(platform_fee)
+            .and_then(|v| v.checked_sub(creator_fee))
+            .ok_or(ErrorCode::Overflow)?;
+
+        **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(net_payout);
+        **user_ai.try_borrow_mut_lamports()? = user_ai.lamports().saturating_add(net_payout);
+
+        if platform_fee > 0 {
+            **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(platform_fee);
+            **treasury_ai.try_borrow_mut_lamports()? = treasury_ai.lamports().saturating_add(platform_fee);
+        }
+        market.creator_fee_escrow = market
+            .creator_fee_escrow
+            .checked_add(creator_fee)
+            .ok_or(ErrorCode::Overflow)?;
+
+        pos.claimed = true;
+
+        if market.fee_mode == FEE_MODE_SETTLEMENT {
+            emit!(SettlementFeeCharged {
+                market: market.key(),
+                user: ctx.accounts.user.key(),
+                payout_lamports: payout,
+                platform_fee_lamports: platform_fee,
+                creator_fee_lamports: creator_fee,
+            });
+        }
+
+        emit!(Claimed {
+            market: market.key(),
+            user: ctx.accounts.user.key(),
+            kind: ClaimKind::Winnings,
+            amount_lamports: net_payout,
+        });
+
+        Ok(())
+    }
+
+    /* ---------- CLAIM REFUND (cancelled) ---------- */
+
+    pub fn claim_refund(ctx: Context<ClaimRefund>) -> Result<()> {
+        let market_ai = ctx.accounts.market.to_account_info();
+        let user_ai = ctx.accounts.user.to_account_info();
+
+        let market = &mut ctx.accounts.market;
+        let outcomes = &mut ctx.accounts.market_outcomes;
+        let pos = &mut ctx.accounts.user_position;
+
+        require!(market.cancelled, ErrorCode::NotCancelled);
+        require!(market.status == MarketStatus::Cancelled, ErrorCode::InvalidState);
+        require!(!market.resolved, ErrorCode::InvalidState);
+
+        require!(!pos.claimed, ErrorCode::AlreadyClaimed);
+
+        require!(pos.market == market.key(), ErrorCode::InvalidUserPosition);
+        require!(pos.user == ctx.accounts.user.key(), ErrorCode::InvalidUserPosition);
+
+        // voided outcomes are paid from the void reserve, the rest of the basis from the pool
+        let voided: Vec<usize> = pos
+            .entries
+            .iter()
+            .map(|e| e.outcome_index as usize)
+            .filter(|&i| market.is_voided(i))
+            .collect();
+        let mut void_refund: u64 = 0;
+        for idx in voided {
+            let refund = release_voided_entry(market, outcomes, pos, idx)?;
+            void_refund = void_refund.checked_add(refund).ok_or(ErrorCode::Overflow)?;
+        }
+
+        let refund_u64: u64 =
+            u64::try_from(pos.net_cost_lamports.max(0)).map_err(|_| error!(ErrorCode::Overflow))?;
+        let total = void_refund.checked_add(refund_u64).ok_or(ErrorCode::Overflow)?;
+        require!(total > 0, ErrorCode::NothingToRefund);
+
+        cover_shortfall(
+            &mut ctx.accounts.insurance_fund,
+            market,
+            &market_ai,
+            total,
+            ctx.accounts.user.key(),
+            ClaimKind::Refund,
+        )?;
+
+        **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(total);
+        **user_ai.try_borrow_mut_lamports()? = user_ai.lamports().saturating_add(total);
+
+        pos.net_cost_lamports = 0;
+        pos.claimed = true;
+
+        if void_refund > 0 {
+            emit!(Claimed {
+                market: market.key(),
+                user: ctx.accounts.user.key(),
+                kind: ClaimKind::OutcomeRefund,
+                amount_lamports: void_refund,
+            });
+        }
+        if refund_u64 > 0 {
+            emit!(Claimed {
+                market: market.key(),
+                user: ctx.accounts.user.key(),
+                kind: ClaimKind::Refund,
+                amount_lamports: refund_u64,
+            });
+        }
+
+        Ok(())
+    }
+
+    /* ---------- CLAIM OUTCOME REFUND (voided outcome) ---------- */
+
+    pub fn claim_outcome_refund(ctx: Context<ClaimOutcomeRefund>, outcome_index: u8) -> Result<()> {
+        let market_ai = ctx.accounts.market.to_account_info();
+        let user_ai = ctx.accounts.user.to_account_info();
+
+        let market = &mut ctx.accounts.market;
+        let outcomes = &mut ctx.accounts.market_outcomes;
+        let pos = &mut ctx.accounts.user_position;
+
+        // no claimed check: voided stakes stay refundable after claim_winnings,
+        // and release_voided_entry drops the entry so it can't be paid twice
+        require!(pos.market == market.key(), ErrorCode::InvalidUserPosition);
+        require!(pos.user == ctx.accounts.user.key(), ErrorCode::InvalidUserPosition);
+
+        let idx = outcome_index as usize;
+        require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+        require!(market.is_voided(idx), ErrorCode::OutcomeNotVoided);
+
+        // q moves while the market may still be trading
+        if market.status == MarketStatus::Open {
+            accrue_twap(market, outcomes, Clock::get()?.unix_timestamp)?;
+        }
+
+        let refund = release_voided_entry(market, outcomes, pos, idx)?;
+        require!(market_ai.lamports() >= refund, ErrorCode::InsufficientMarketBalance);
+
+        if refund > 0 {
+            **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(refund);
+            **user_ai.try_borrow_mut_lamports()? = user_ai.lamports().saturating_add(refund);
+        }
+
+        emit!(Claimed {
+            market: market.key(),
+            user: ctx.accounts.user.key(),
+            kind: ClaimKind::OutcomeRefund,
+            amount_lamports: refund,
+        });
+
+        Ok(())
+    }
+}
+
+/* ============================== PRICING HELPERS ============================== */
+
+fn linear_cost_lamports(market: &Market, start_supply: u64, shares: u64) -> Result<u64> {
+    require!(shares > 0, ErrorCode::InvalidShares);
+
+    let base = market.base_price_lamports as u128;
+    let slope = market.slope_lamports_per_supply as u128;
+
+    let s = shares as u128;
+    let q0 = start_supply as u128;
+
+    let base_part = s.checked_mul(base).ok_or(ErrorCode::Overflow)?;
+
+    let two_q0 = q0.checked_mul(2).ok_or(ErrorCode::Overflow)?;
+    let inside = two_q0
+        .checked_add(s.checked_sub(1).ok_or(ErrorCode::Overflow)?)
+        .ok_or(ErrorCode::Overflow)?;
+    let series = s
+        .checked_mul(inside)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div(2)
+        .ok_or(ErrorCode::Overflow)?;
+
+    let slope_part = slope.checked_mul(series).ok_or(ErrorCode::Overflow)?;
+
+    let total = base_part.checked_add(slope_part).ok_or(ErrorCode::Overflow)?;
+    Ok(u64::try_from(total).map_err(|_| error!(ErrorCode::Overflow))?)
+}
+
+/// Lamports to buy `shares` of outcome `idx` under the market's pricing model.
+fn curve_buy_cost(market: &Market, outcomes: &MarketOutcomes, idx: usize, shares: u64) -> Result<u64> {
+    // parimutuel: a share is a lamport staked
+    if market.is_parimutuel() {
+        return Ok(shares);
+    }
+
+    match market.pricing_model {
+        PRICING_FPMM => {
+            let sets = math_fpmm::fpmm_sets_to_buy(&outcomes.reserves, idx, shares)?;
+            sets.checked_mul(market.set_price_lamports)
+                .ok_or_else(|| error!(ErrorCode::Overflow))
+        }
+        PRICING_LMSR => {
+            let q = lmsr_q_lamports(market, outcomes)?;
+            let amount = shares.checked_mul(market.set_price_lamports).ok_or(ErrorCode::Overflow)?;
+            math_lmsr::lmsr_buy_cost(&q, market.b_lamports, idx as u8, amount, market.outcome_count)
+        }
+        PRICING_LS_LMSR => {
+            let q = lmsr_q_lamports(market, outcomes)?;
+            let amount = shares.checked_mul(market.set_price_lamports).ok_or(ErrorCode::Overflow)?;
+            let alpha = math_lmsr::ls_lmsr_alpha(LS_LMSR_VIG_BPS, market.outcome_count)?;
+            math_lmsr::ls_lmsr_buy_cost(&q, alpha, idx as u8, amount, market.outcome_count)
+        }
+        _ => linear_cost_lamports(market, outcomes.q[idx], shares),
+    }
+}
+
+/// Lamports released by selling `shares` of outcome `idx` (caller checks supply).
+fn curve_sell_refund(market: &Market, outcomes: &MarketOutcomes, idx: usize, shares: u64) -> Result<u64> {
+    // parimutuel stakes are final until resolution (or cancellation refund)
+    require!(!market.is_parimutuel(), ErrorCode::SellDisabled);
+
+    match market.pricing_model {
+        PRICING_FPMM => {
+            let sets = math_fpmm::fpmm_sets_for_sell(&outcomes.reserves, idx, shares)?;
+            sets.checked_mul(market.set_price_lamports)
+                .ok_or_else(|| error!(ErrorCode::Overflow))
+        }
+        PRICING_LMSR => {
+            let q = lmsr_q_lamports(market, outcomes)?;
+            let amount = shares.checked_mul(market.set_price_lamports).ok_or(ErrorCode::Overflow)?;
+            math_lmsr::lmsr_sell_refund(&q, market.b_lamports, idx as u8, amount, market.outcome_count)
+        }
+        PRICING_LS_LMSR => {
+            let q = lmsr_q_lamports(market, outcomes)?;
+            let amount = shares.checked_mul(market.set_price_lamports).ok_or(ErrorCode::Overflow)?;
+            let alpha = math_lmsr::ls_lmsr_alpha(LS_LMSR_VIG_BPS, market.outcome_count)?;
+            math_lmsr::ls_lmsr_sell_refund(&q, alpha, idx as u8, amount, market.outcome_count)
+        }
+        _ => {
+            let start_supply = outcomes.q[idx].checked_sub(shares).ok_or(ErrorCode::InsufficientShares)?;
+            linear_cost_lamports(market, start_supply, shares)
+        }
+    }
+}
+
+/// Current marginal price of one share of outcome `idx`, in lamports.
+fn spot_price_lamports(market: &Market, outcomes: &MarketOutcomes, idx: usize) -> Result<u64> {
+    spot_prices_lamports(market, outcomes)?
+        .get(idx)
+        .copied()
+        .ok_or(error!(ErrorCode::InvalidOutcomeIndex))
+}
+
+/// Every outcome's spot price in one pass, so LMSR's normalizer is computed once.
+fn spot_prices_lamports(market: &Market, outcomes: &MarketOutcomes) -> Result<Vec<u64>> {
+    let n = market.outcome_count;
+    let live = &outcomes.q[..n as usize];
+
+    // parimutuel: implied probability is the outcome's share of the pool
+    if market.is_parimutuel() {
+        let pool: u128 = live.iter().map(|q| *q as u128).sum();
+        return Ok(live
+            .iter()
+            .map(|q| {
+                (market.set_price_lamports as u128 * *q as u128)
+                    .checked_div(pool)
+                    .unwrap_or(market.set_price_lamports as u128 / n as u128) as u64
+            })
+            .collect());
+    }
+
+    let scaled: Vec<u128> = match market.pricing_model {
+        PRICING_FPMM => {
+            // p_i is proportional to 1 / r_i
+            let inv = |r: u64| -> Result<u128> {
+                require!(r > 0, ErrorCode::NoLiquidity);
+                Ok(math_lmsr::SCALE * math_lmsr::SCALE / r as u128)
+            };
+            let invs = outcomes
+                .reserves
+                .iter()
+                .take(n as usize)
+                .map(|r| inv(*r))
+                .collect::<Result<Vec<u128>>>()?;
+            let mut sum = 0u128;
+            for v in invs.iter() {
+                sum = sum.checked_add(*v).ok_or(ErrorCode::Overflow)?;
+            }
+            invs.iter().map(|v| v * math_lmsr::SCALE / sum).collect()
+        }
+        PRICING_LMSR => {
+            let q = lmsr_q_lamports(market, outcomes)?;
+            math_lmsr::lmsr_prices(&q, market.b_lamports, n)?
+                .into_iter()
+                .map(u128::from)
+                .collect()
+        }
+        PRICING_LS_LMSR => {
+            let q = lmsr_q_lamports(market, outcomes)?;
+            let alpha = math_lmsr::ls_lmsr_alpha(LS_LMSR_VIG_BPS, n)?;
+            math_lmsr::ls_lmsr_prices(&q, alpha, n)?
+                .into_iter()
+                .map(u128::from)
+                .collect()
+        }
+        _ => return live.iter().map(|q| linear_cost_lamports(market, *q, 1)).collect(),
+    };
+
+    scaled
+        .into_iter()
+        .map(|p| {
+            let price = p
+                .checked_mul(market.set_price_lamports as u128)
+                .ok_or(ErrorCode::Overflow)?
+                / math_lmsr::SCALE;
+            u64::try_from(price).map_err(|_| error!(ErrorCode::Overflow))
+        })
+        .collect()
+}
+
+/// Folds the post-trade spot price of every live outcome into its current candle, or
+/// opens a new one at the ring head; `traded` credits volume to the outcomes that traded.
+/// No-op when the market has no PriceHistory or no price yet.
+fn record_candle(
+    price_history: &Option<AccountLoader<PriceHistory>>,
+    market: &Market,
+    outcomes: &MarketOutcomes,
+    now: i64,
+    traded: &[(usize, u64)],
+) -> Result<()> {
+    let Some(loader) = price_history else {
+        return Ok(());
+    };
+    // one trade moves every outcome's price under lmsr and fpmm
+    let Ok(prices) = spot_prices_lamports(market, outcomes) else {
+        return Ok(());
+    };
+    let mut history = loader.load_mut()?;
+    let bucket = now - now.rem_euclid(history.interval_seconds);
+
+    for (idx, price) in prices.into_iter().enumerate() {
+        if market.is_voided(idx) {
+            continue;
+        }
+        let volume = traded
+            .iter()
+            .filter(|(i, _)| *i == idx)
+            .fold(0u64, |acc, (_, v)| acc.saturating_add(*v));
+        history.record(idx, bucket, price, volume);
+    }
+    Ok(())
+}
+
+/// Uniswap-v2 style: adds each outcome's spot price times the seconds since the
+/// last update, using the price that held over that interval. Call before q moves.
+fn accrue_twap(market: &mut Market, outcomes: &mut MarketOutcomes, now: i64) -> Result<()> {
+    let elapsed = now.saturating_sub(market.twap_last_ts);
+    if elapsed <= 0 {
+        return Ok(());
+    }
+
+    // a pool without liquidity yet has no price; the first real price covers the gap
+    let Ok(prices) = spot_prices_lamports(market, outcomes) else {
+        return Ok(());
+    };
+    for (cumulative, price) in outcomes.price_cumulative.iter_mut().zip(prices) {
+        *cumulative = cumulative.wrapping_add(price as u128 * elapsed as u128);
+    }
+
+    market.twap_last_ts = now;
+    Ok(())
+}
+
+/// Cumulative price of `idx` as of `now`, without writing it back.
+fn observe_cumulative(market: &Market, outcomes: &MarketOutcomes, idx: usize, now: i64) -> u128 {
+    let elapsed = now.saturating_sub(market.twap_last_ts).max(0);
+    let price = spot_price_lamports(market, outcomes, idx).unwrap_or(0);
+    outcomes.price_cumulative[idx].wrapping_add(price as u128 * elapsed as u128)
+}
+
+/// LMSR inputs in lamports: (trader shares + pool inventory) * unit, where one
+/// share pays `set_price_lamports` at resolution.
+fn lmsr_q_lamports(market: &Market, outcomes: &MarketOutcomes) -> Result<Vec<u64>> {
+    require!(market.subsidy_lamports > 0, ErrorCode::NoLiquidity);
+
+    outcomes
+        .q
+        .iter()
+        .zip(outcomes.reserves.iter())
+        .map(|(q, r)| {
+            q.checked_add(*r)
+                .and_then(|v| v.checked_mul(market.set_price_lamports))
+                .ok_or_else(|| error!(ErrorCode::Overflow))
+        })
+        .collect()
+}
+
+/// b such that the LMSR worst-case loss b * ln(n) equals `subsidy`.
+fn lmsr_b_for_subsidy(subsidy: u64, outcome_count: u8) -> Result<u64> {
+    let n = (outcome_count as u128)
+        .checked_mul(math_lmsr::SCALE)
+        .ok_or(ErrorCode::Overflow)?;
+    let ln_n = math_lmsr::ln_fixed(n)?;
+    require!(ln_n > 0, ErrorCode::InvalidB);
+
+    let b = (subsidy as u128)
+        .checked_mul(math_lmsr::SCALE)
+        .ok_or(ErrorCode::Overflow)?
+        / ln_n;
+    u64::try_from(b).map_err(|_| error!(ErrorCode::Overflow))
+}
+
+/// Lamports needed to pay the largest outcome's shares (incl. complete sets) at `unit`.
+fn graduation_backing(market: &Market, outcomes: &MarketOutcomes, unit: u64) -> Result<u64> {
+    let max_supply = outcomes.q.iter().copied().max().unwrap_or(0);
+    max_supply
+        .checked_add(market.complete_sets)
+        .and_then(|v| v.checked_mul(unit))
+        .ok_or_else(|| error!(ErrorCode::Overflow))
+}
+
+/// Graduation pool: (unit per share, b, pool inventory).
+///
+/// The unit spends GRADUATION_BACKING_BPS of collateral on the largest outcome's
+/// liability. LMSR state x_i = K - b * ln(1/p_i) reproduces the linear curve's
+/// implied probabilities p_i; with b = subsidy / max ln(1/p_i), worst-case loss
+/// K + sets * unit stays within the collateral.
+fn lmsr_graduation_seed(
+    market: &Market,
+    outcomes: &MarketOutcomes,
+    collateral: u64,
+) -> Result<(u64, u64, Vec<u64>)> {
+    let max_supply = outcomes
+        .q
+        .iter()
+        .copied()
+        .max()
+        .unwrap_or(0)
+        .checked_add(market.complete_sets)
+        .ok_or(ErrorCode::Overflow)?;
+    require!(max_supply > 0, ErrorCode::InvalidSupply);
+
+    let unit = collateral.saturating_mul(GRADUATION_BACKING_BPS) / 10_000 / max_supply;
+    require!(unit > 0, ErrorCode::InsufficientMarketBalance);
+
+    let subsidy = collateral
+        .checked_sub(graduation_backing(market, outcomes, unit)?)
+        .ok_or(ErrorCode::Overflow)?;
+
+    // implied probabilities from the linear marginal prices
+    let prices: Vec<u128> = outcomes
+        .q
+        .iter()
+        .map(|q| market.base_price_lamports as u128 + market.slope_lamports_per_supply as u128 * *q as u128)
+        .collect();
+    let price_sum: u128 = prices.iter().sum();
+
+    // ln(1/p_i) = ln(sum / price_i), scaled
+    let inv_ln: Vec<u128> = prices
+        .iter()
+        .map(|p| {
+            let ratio = price_sum
+                .checked_mul(math_lmsr::SCALE)
+                .ok_or_else(|| error!(ErrorCode::Overflow))?
+                / p;
+            math_lmsr::ln_fixed(ratio)
+        })
+        .collect::<Result<_>>()?;
+    let max_inv_ln = inv_ln.iter().copied().max().unwrap_or(0);
+    require!(max_inv_ln > 0, ErrorCode::InvalidB);
+
+    let b = (subsidy as u128)
+        .checked_mul(math_lmsr::SCALE)
+        .ok_or(ErrorCode::Overflow)?
+        / max_inv_ln;
+    let b = u64::try_from(b).map_err(|_| error!(ErrorCode::Overflow))?;
+    require!(b > 0, ErrorCode::InvalidB);
+
+    // shift so every outcome's state covers its trader shares
+    let offsets: Vec<u128> = inv_ln
+        .iter()
+        .map(|l| b as u128 * l / math_lmsr::SCALE)
+        .collect();
+    let k = outcomes
+        .q
+        .iter()
+        .zip(offsets.iter())
+        .map(|(q, off)| *q as u128 * unit as u128 + off)
+        .max()
+        .unwrap_or(0);
+
+    let reserves = outcomes
+        .q
+        .iter()
+        .zip(offsets.iter())
+        .map(|(q, off)| {
+            let r = (k - off) / unit as u128;
+            u64::try_from(r.saturating_sub(*q as u128)).map_err(|_| error!(ErrorCode::Overflow))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((unit, b, reserves))
+}
+
+/// Even per-outcome ls-lmsr seed (in shares) whose worst-case loss is `subsidy`.
+fn ls_lmsr_seed_shares(market: &Market, subsidy: u64) -> Result<u64> {
+    let seed_lamports = (subsidy as u128)
+        .checked_mul(10_000u128)
+        .ok_or(ErrorCode::Overflow)?
+        / LS_LMSR_VIG_BPS as u128;
+    let seed = seed_lamports / market.set_price_lamports as u128;
+    u64::try_from(seed).map_err(|_| error!(ErrorCode::Overflow))
+}
+
+/// Largest share count (<= max_shares) whose curve cost fits in `budget`, with its cost.
+fn shares_for_budget(
+    market: &Market,
+    outcomes: &MarketOutcomes,
+    idx: usize,
+    budget: u64,
+    max_shares: u64,
+) -> Result<(u64, u64)> {
+    let (mut lo, mut hi) = (0u64, max_shares);
+    let mut best_cost = 0u64;
+
+    while lo < hi {
+        let mid = lo + (hi - lo).div_ceil(2);
+        let c = curve_buy_cost(market, outcomes, idx, mid)?;
+        if c <= budget {
+            lo = mid;
+            best_cost = c;
+        } else {
+            hi = mid - 1;
+        }
+    }
+
+    Ok((lo, best_cost))
+}
+
+/* ============================== FEES ============================== */
+
+/// Fees charged on one trade leg (lamports).
+#[derive(Clone, Copy, Default)]
+struct TradeFees {
+    platform: u64,
+    creator: u64,
+    lp: u64,
+    referrer: u64, // carved out of the platform fee
+    rebate: u64,   // maker rebate already netted out of the platform fee (not in total)
+    platform_bps: u64, // rate applied, before rebate and referral split
+}
+
+/// Platform fee a trader pays after volume tiers and maker rebates.
+#[derive(Clone, Copy)]
+struct FeeDiscount {
+    platform_bps: u64,
+    rebate_bps: u64,
+}
+
+impl Default for FeeDiscount {
+    fn default() -> Self {
+        FeeDiscount { platform_bps: PLATFORM_FEE_BPS, rebate_bps: 0 }
+    }
+}
+
+impl FeeDiscount {
+    /// Base platform fee with the market's dynamic adjustment at `now`.
+    fn at(market: &Market, now: i64) -> Self {
+        FeeDiscount {
+            platform_bps: dynamic_platform_bps(market, PLATFORM_FEE_BPS, now),
+            rebate_bps: 0,
+        }
+    }
+
+    /// Highest platform fee the market can charge; sizes escrows for later fills.
+    fn worst_case(market: &Market) -> Self {
+        FeeDiscount {
+            platform_bps: PLATFORM_FEE_BPS.max(market.dynamic_fee_max_bps as u64),
+            rebate_bps: 0,
+        }
+    }
+}
+
+impl TradeFees {
+    fn total(&self) -> u64 {
+        self.platform
+            .saturating_add(self.creator)
+            .saturating_add(self.lp)
+            .saturating_add(self.referrer)
+    }
+
+    fn accumulate(&mut self, other: &TradeFees) {
+        self.platform = self.platform.saturating_add(other.platform);
+        self.creator = self.creator.saturating_add(other.creator);
+        self.lp = self.lp.saturating_add(other.lp);
+        self.referrer = self.referrer.saturating_add(other.referrer);
+        self.rebate = self.rebate.saturating_add(other.rebate);
+        self.platform_bps = self.platform_bps.max(other.platform_bps);
+    }
+
+    /// Moves `share_bps` of the platform fee to the referrer; the total is unchanged.
+    fn with_referral(mut self, share_bps: u16) -> Self {
+        let cut = self.platform.saturating_mul(share_bps as u64) / 10_000;
+        self.platform -= cut;
+        self.referrer = cut;
+        self
+    }
+}
+
+/// Fees at a trader's tiered platform rate; the maker rebate is capped at the platform fee.
+fn trade_fees_with(market: &Market, amount: u64, discount: &FeeDiscount) -> TradeFees {
+    let lp = if market.pricing_model != PRICING_LINEAR && market.lp_supply > 0 {
+        amount.saturating_mul(market.lp_fee_bps as u64) / 10_000
+    } else {
+        0
+    };
+
+    // settlement mode: platform and creator take their cut from winnings instead
+    if market.fee_mode == FEE_MODE_SETTLEMENT {
+        return TradeFees { lp, ..TradeFees::default() };
+    }
+
+    let platform = amount.saturating_mul(discount.platform_bps) / 10_000;
+    let rebate = (amount.saturating_mul(discount.rebate_bps) / 10_000).min(platform);
+
+    TradeFees {
+        platform: platform - rebate,
+        creator: amount.saturating_mul(market.creator_fee_bps as u64) / 10_000,
+        lp,
+        referrer: 0,
+        rebate,
+        platform_bps: discount.platform_bps,
+    }
+}
+
+/// Tiered platform fee for a trader from their rolling volume; base fee without stats.
+/// Rolls the stats window forward, so volume can be recorded right after.
+fn fee_discount(
+    market: &Market,
+    config: &Option<Account<ProtocolConfig>>,
+    stats: &mut Option<Account<TraderStats>>,
+    now: i64,
+) -> FeeDiscount {
+    let mut discount = FeeDiscount::default();
+    // stats are rolled whenever present so volume lands in today's bucket
+    if let Some(stats) = stats.as_mut() {
+        stats.roll(now);
+    }
+    let (Some(config), Some(stats)) = (config, stats) else {
+        return FeeDiscount::at(market, now);
+    };
+
+    let volume = stats.volume_30d();
+
+    // tiers ascend by volume; the last one reached applies
+    for tier in config.tiers.iter() {
+        if volume >= tier.min_volume_lamports {
+            discount.platform_bps = tier.platform_fee_bps as u64;
+        }
+    }
+    if stats.market_maker {
+        discount.rebate_bps = config.maker_rebate_bps as u64;
+    }
+    discount.platform_bps = dynamic_platform_bps(market, discount.platform_bps, now);
+    discount
+}
+
+/// Raises `base_bps` toward the market's dynamic ceiling: linearly over the last
+/// `dynamic_fee_ramp_seconds` before resolution, plus a share of recent volatility.
+fn dynamic_platform_bps(market: &Market, base_bps: u64, now: i64) -> u64 {
+    let max_bps = market.dynamic_fee_max_bps as u64;
+    if max_bps <= base_bps {
+        return base_bps;
+    }
+    let headroom = max_bps - base_bps;
+
+    let ramp = market.dynamic_fee_ramp_seconds;
+    let remaining = market.resolution_time.saturating_sub(now).max(0);
+    let time_bps = if ramp > 0 && remaining < ramp {
+        headroom * (ramp - remaining) as u64 / ramp as u64
+    } else {
+        0
+    };
+
+    let vol_bps = decayed_volatility(market, now).saturating_mul(VOL_FEE_MULT_BPS) / 10_000;
+
+    base_bps.saturating_add(time_bps).saturating_add(vol_bps).min(max_bps)
+}
+
+/// Volatility EMA faded by the time since the last trade.
+fn decayed_volatility(market: &Market, now: i64) -> u64 {
+    let elapsed = now.saturating_sub(market.volatility_last_ts).max(0) as u64;
+    market.volatility_ema_bps * VOL_DECAY_SECONDS as u64 / (VOL_DECAY_SECONDS as u64 + elapsed)
+}
+
+/// Folds a trade's spot move on one outcome (bps of the set price) into the EMA.
+fn track_volatility(market: &mut Market, outcomes: &MarketOutcomes, idx: usize, before: Option<u64>, now: i64) {
+    let (Some(before), Ok(after)) = (before, spot_price_lamports(market, outcomes, idx)) else {
+        return;
+    };
+    let Some(move_bps) = (before.abs_diff(after) as u128 * 10_000).checked_div(market.set_price_lamports as u128)
+    else {
+        return;
+    };
+    let move_bps = u64::try_from(move_bps).unwrap_or(u64::MAX);
+
+    let prev = decayed_volatility(market, now);
+    market.volatility_ema_bps = prev
+        .saturating_mul(VOL_EMA_WEIGHT - 1)
+        .saturating_add(move_bps)
+        / VOL_EMA_WEIGHT;
+    market.volatility_last_ts = now;
+}
+
+/// Adds a trade to the trader's current day bucket (after fee_discount rolled it).
+fn record_volume(stats: &mut Option<Account<TraderStats>>, amount: u64, fees: &TradeFees, now: i64) {
+    if let Some(stats) = stats {
+        stats.record(now, amount, fees.rebate);
+    }
+}
+
+/// Referral share of the platform fee for this trade (0 without a referrer).
+fn referral_share(referrer: &Option<Account<Referrer>>, trader: Pubkey) -> Result<u16> {
+    match referrer {
+        Some(r) => {
+            require_keys_neq!(r.wallet, trader, ErrorCode::SelfReferral);
+            Ok(r.share_bps)
+        }
+        None => Ok(0),
+    }
+}
+
+/// Accrues the referrer's cut (already in the market account) to the referrer PDA.
+fn pay_referrer(referrer: &mut Option<Account<Referrer>>, market_ai: &AccountInfo, fees: &TradeFees) -> Result<()> {
+    let Some(r) = referrer else {
+        return Ok(());
+    };
+    if fees.referrer == 0 {
+        return Ok(());
+    }
+
+    let referrer_ai = r.to_account_info();
+    require!(market_ai.lamports() >= fees.referrer, ErrorCode::InsufficientMarketBalance);
+    **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(fees.referrer);
+    **referrer_ai.try_borrow_mut_lamports()? = referrer_ai.lamports().saturating_add(fees.referrer);
+
+    r.accrued_lamports = r.accrued_lamports.checked_add(fees.referrer).ok_or(ErrorCode::Overflow)?;
+    r.total_earned_lamports = r.total_earned_lamports.saturating_add(fees.referrer);
+    Ok(())
+}
+
+/// Routes fees already sitting in the market account:
+/// platform fee to the treasury, creator fee escrowed, LP fee accrued per LP share.
+fn collect_fees(
+    market: &mut Market,
+    market_ai: &AccountInfo,
+    treasury_ai: &AccountInfo,
+    fees: &TradeFees,
+) -> Result<()> {
+    // part of the platform fee is held back as the creator's graduation bonus
+    let withheld = if market.pricing_model == PRICING_LINEAR && !market.graduated {
+        fees.platform.saturating_mul(market.graduation_bonus_bps as u64) / 10_000
+    } else {
+        0
+    };
+    let platform = fees.platform - withheld;
+
+    if platform > 0 {
+        **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(platform);
+        **treasury_ai.try_borrow_mut_lamports()? = treasury_ai.lamports().saturating_add(platform);
+    }
+
+    if withheld > 0 {
+        market.graduation_bonus_lamports = market
+            .graduation_bonus_lamports
+            .checked_add(withheld)
+            .ok_or(ErrorCode::Overflow)?;
+    }
+
+    if fees.creator > 0 {
+        market.creator_fee_escrow = market
+            .creator_fee_escrow
+            .checked_add(fees.creator)
+            .ok_or(ErrorCode::Overflow)?;
+    }
+
+    if fees.lp > 0 {
+        market.lp_fee_pool_lamports = market
+            .lp_fee_pool_lamports
+            .checked_add(fees.lp)
+            .ok_or(ErrorCode::Overflow)?;
+        let per_share = (fees.lp as u128)
+            .checked_mul(LP_FEE_SCALE)
+            .ok_or(ErrorCode::Overflow)?
+            / market.lp_supply as u128;
+        market.lp_fee_per_share = market
+            .lp_fee_per_share
+            .checked_add(per_share)
+            .ok_or(ErrorCode::Overflow)?;
+    }
+
+    Ok(())
+}
+
+/* ============================== TRADE INNER ============================== */
+
+/// Single-outcome trade. `max_pay_lamports` bounds a buy's cost plus fees.
+fn trade_inner(
+    accounts: &mut Trade,
+    shares: u64,
+    outcome_index: u8,
+    is_buy: bool,
+    max_pay_lamports: u64,
+) -> Result<()> {
+    let trader_key = accounts.trader.key();
+    let market_key = accounts.market.key();
+
+    let trader_ai = accounts.trader.to_account_info();
+    let market_ai = accounts.market.to_account_info();
+    let pos_ai = accounts.user_position.to_account_info();
+    let system_ai = accounts.system_program.to_account_info();
+    let treasury_ai = accounts.treasury.to_account_info();
+
+    let market = &mut accounts.market;
+    let outcomes = &mut accounts.market_outcomes;
+    let pos = &mut accounts.user_position;
+
+    let now = require_market_tradable(market)?;
+    require!(market.batch_seconds == 0, ErrorCode::BatchAuctionOnly);
+
+    accrue_twap(market, outcomes, now)?;
+
+    require!(shares > 0, ErrorCode::InvalidShares);
+    require!(shares <= market.max_trade_shares, ErrorCode::TradeTooLarge);
+
+    init_or_check_position(pos, market_key, trader_key)?;
+
+    if market.cooldown_seconds > 0 && pos.last_trade_ts > 0 {
+        require!(
+            now - pos.last_trade_ts >= market.cooldown_seconds,
+            ErrorCode::CooldownActive
+        );
+    }
+
+    let idx = outcome_index as usize;
+    require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+    require!(!market.is_voided(idx), ErrorCode::OutcomeVoided);
+
+    let referral_bps = referral_share(&accounts.referrer, trader_key)?;
+    let discount = fee_discount(market, &accounts.protocol_config, &mut accounts.trader_stats, now);
+    let spot_before = spot_price_lamports(market, outcomes, idx).ok();
+
+    if is_buy {
+        let cost = curve_buy_cost(market, outcomes, idx, shares)?;
+        require!(cost > 0, ErrorCode::InvalidCost);
+
+        let fees = trade_fees_with(market, cost, &discount).with_referral(referral_bps);
+
+        let total_pay = cost
+            .checked_add(fees.total()).ok_or(ErrorCode::Overflow)?;
+        require!(total_pay <= max_pay_lamports, ErrorCode::SlippageExceeded);
+
+        invoke(
+            &system_instruction::transfer(&trader_key, &market_key, total_pay),
+            &[trader_ai.clone(), market_ai.clone(), system_ai.clone()],
+        )?;
+
+        // platform fee to the treasury, creator fee escrowed in market
+        collect_fees(market, &market_ai, &treasury_ai, &fees)?;
+        pay_referrer(&mut accounts.referrer, &market_ai, &fees)?;
+
+        record_buy(market, outcomes, pos, idx, shares, cost)?;
+        record_candle(&accounts.price_history, market, outcomes, now, &[(idx, cost)])?;
+        record_volume(&mut accounts.trader_stats, cost, &fees, now);
+        track_volatility(market, outcomes, idx, spot_before, now);
+        pos.last_trade_ts = now;
+
+        pos.net_cost_lamports = pos
+            .net_cost_lamports
+            .checked_add(cost as i128)
+            .ok_or(ErrorCode::Overflow)?;
+
+        enforce_position_cap(market, outcomes, pos, idx)?;
+
+        // a new outcome entry may not fit in the position account yet
+        let needed = 8 + pos.try_to_vec()?.len();
+        grow_account(&pos_ai, &trader_ai, &system_ai, needed)?;
+
+        emit!(TradeExecuted {
+            market: market.key(),
+            user: trader_key,
+            is_buy: true,
+            outcome_index,
+            shares,
+            amount_lamports: cost,
+            platform_fee_lamports: fees.platform,
+            creator_fee_lamports: fees.creator,
+            lp_fee_lamports: fees.lp,
+            referrer_fee_lamports: fees.referrer,
+            platform_fee_bps: fees.platform_bps as u16,
+        });
+
+        Ok(())
+    } else {
+        let (refund, fees) = sell_to_curve(
+            market, outcomes, pos, idx, shares, &discount, referral_bps,
+            &market_ai, &trader_ai, &treasury_ai,
+        )?;
+        pay_referrer(&mut accounts.referrer, &market_ai, &fees)?;
+
+        record_candle(&accounts.price_history, market, outcomes, now, &[(idx, refund)])?;
+        record_volume(&mut accounts.trader_stats, refund, &fees, now);
+        track_volatility(market, outcomes, idx, spot_before, now);
+        pos.last_trade_ts = now;
+
+        emit!(TradeExecuted {
+            market: market.key(),
+            user: trader_key,
+            is_buy: false,
+            outcome_index,
+            shares,
+            amount_lamports: refund,
+            platform_fee_lamports: fees.platform,
+            creator_fee_lamports: fees.creator,
+            lp_fee_lamports: fees.lp,
+            referrer_fee_lamports: fees.referrer,
+            platform_fee_bps: fees.platform_bps as u16,
+        });
+
+        Ok(())
+    }
+}
+
+/* ============================== ACCOUNT HELPERS ============================== */
+
+/// Lamports held for voided-outcome refunds, unclaimed LP fees, escrowed creator fees and a
+/// withheld graduation bonus; none of it belongs to the winners' pool.
+fn reserved_lamports(market: &Market) -> Result<u64> {
+    market
+        .void_reserve_lamports
+        .checked_add(market.lp_fee_pool_lamports)
+        .and_then(|v| v.checked_add(market.creator_fee_escrow))
+        .and_then(|v| v.checked_add(market.graduation_bonus_lamports))
+        .ok_or(error!(ErrorCode::Overflow))
+}
+
+/// Fixes the winners' pool and winning supply at finalization so every claim is paid
+/// against the same totals, however many claims came before it.
+fn snapshot_winning_pool(
+    market: &mut Market,
+    market_ai: &AccountInfo,
+    outcomes: &MarketOutcomes,
+    winning: usize,
+) -> Result<()> {
+    // minted complete sets hold one share of every outcome; the fpmm pool holds reserves
+    market.winning_supply = outcomes.q[winning]
+        .checked_add(market.complete_sets)
+        .and_then(|v| v.checked_add(outcomes.reserves[winning]))
+        .ok_or(ErrorCode::Overflow)?;
+    let rent_floor = Rent::get()?.minimum_balance(market_ai.data_len());
+    market.winning_pool_lamports = market_ai
+        .lamports()
+        .saturating_sub(reserved_lamports(market)?)
+        .saturating_sub(rent_floor);
+    Ok(())
+}
+
+/// Subsidy-market balance not owed to winners, refunds, fees or rent.
+fn subsidy_residual(market: &Market, market_ai: &AccountInfo, outcomes: &MarketOutcomes) -> Result<u64> {
+    let winning = market.winning_outcome.ok_or(ErrorCode::MarketNotResolved)? as usize;
+    let unclaimed = outcomes.q[winning]
+        .checked_add(market.complete_sets)
+        .ok_or(ErrorCode::Overflow)?
+        .saturating_sub(market.claimed_winning_shares);
+    let liability = unclaimed
+        .checked_mul(market.set_price_lamports)
+        .ok_or(ErrorCode::Overflow)?;
+    let rent = Rent::get()?.minimum_balance(market_ai.data_len());
+
+    Ok(market_ai
+        .lamports()
+        .saturating_sub(rent)
+        .saturating_sub(market.void_reserve_lamports)
+        .saturating_sub(market.lp_fee_pool_lamports)
+        .saturating_sub(market.creator_fee_escrow)
+        .saturating_sub(liability))
+}
+
+/// Graduation seeds the LMSR subsidy without minting LP shares, so nobody can withdraw
+/// what is left of it; at finalization it goes to the treasury.
+fn sweep_graduation_residual(
+    market: &mut Market,
+    market_ai: &AccountInfo,
+    treasury_ai: &AccountInfo,
+    outcomes: &MarketOutcomes,
+) -> Result<()> {
+    if !market.graduated || market.lp_supply > 0 {
+        return Ok(());
+    }
+
+    let residual = subsidy_residual(market, market_ai, outcomes)?;
+    if residual == 0 {
+        return Ok(());
+    }
+
+    **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(residual);
+    **treasury_ai.try_borrow_mut_lamports()? = treasury_ai.lamports().saturating_add(residual);
+    market.subsidy_lamports = 0;
+
+    emit!(GraduationResidualSwept {
+        market: market_ai.key(),
+        amount_lamports: residual,
+    });
+
+    Ok(())
+}
+
+fn pro_rata_payout(shares: u64, pool: u64, supply: u64) -> Result<u64> {
+    require!(supply > 0, ErrorCode::InvalidSupply);
+    u64::try_from(
+        (shares as u128)
+            .checked_mul(pool as u128)
+            .ok_or(ErrorCode::Overflow)?
+            / supply as u128,
+    )
+    .map_err(|_| error!(ErrorCode::Overflow))
+}
+
+/// Checks the market accepts trades and returns the current timestamp.
+fn require_market_tradable(market: &Market) -> Result<i64> {
+    require!(market.status == MarketStatus::Open, ErrorCode::MarketClosed);
+    require!(!market.resolved, ErrorCode::MarketResolved);
+    require!(!market.cancelled, ErrorCode::InvalidState);
+
+    let now = Clock::get()?.unix_timestamp;
+    require!(now < market.resolution_time, ErrorCode::MarketClosed);
+
+    Ok(now)
+}
+
+/// Complete sets need a set price; parimutuel stakes have none.
+fn require_complete_sets_enabled(market: &Market) -> Result<()> {
+    require!(!market.is_parimutuel(), ErrorCode::InvalidState);
+    Ok(())
+}
+
+/// Initializes a freshly created position, or checks an existing one belongs to (market, user).
+fn init_or_check_position(pos: &mut UserPosition, market_key: Pubkey, user_key: Pubkey) -> Result<()> {
+    if pos.market == Pubkey::default() {
+        pos.market = market_key;
+        pos.user = user_key;
+        pos.claimed = false;
+        pos.last_trade_ts = 0;
+        pos.net_cost_lamports = 0;
+        pos.entries = Vec::new();
+    } else {
+        require!(pos.market == market_key, ErrorCode::InvalidUserPosition);
+        require!(pos.user == user_key, ErrorCode::InvalidUserPosition);
+    }
+    Ok(())
+}
+
+/// Books bought shares on the curve (and FPMM pool), supply and position (with cost basis).
+fn record_buy(
+    market: &mut Market,
+    outcomes: &mut MarketOutcomes,
+    pos: &mut UserPosition,
+    idx: usize,
+    shares: u64,
+    cost: u64,
+) -> Result<()> {
+    if market.pricing_model == PRICING_FPMM {
+        // pool mints `sets` complete sets and hands out `shares` of idx
+        let sets = cost / market.set_price_lamports;
+        for r in outcomes.reserves.iter_mut() {
+            *r = r.checked_add(sets).ok_or(ErrorCode::Overflow)?;
+        }
+        outcomes.reserves[idx] = outcomes.reserves[idx]
+            .checked_sub(shares)
+            .ok_or(ErrorCode::NoLiquidity)?;
+    }
+
+    market.volume_lamports = market.volume_lamports.checked_add(cost).ok_or(ErrorCode::Overflow)?;
+
+    outcomes.q[idx] = outcomes.q[idx].checked_add(shares).ok_or(ErrorCode::Overflow)?;
+    outcomes.outcome_cost[idx] = outcomes.outcome_cost[idx].checked_add(cost).ok_or(ErrorCode::Overflow)?;
+
+    let entry = pos.entry_mut(idx);
+    entry.shares = entry.shares.checked_add(shares).ok_or(ErrorCode::Overflow)?;
+    entry.cost_lamports = entry.cost_lamports.checked_add(cost).ok_or(ErrorCode::Overflow)?;
+    Ok(())
+}
+
+/// Books sold shares; cost basis released is capped so it never goes negative.
+fn record_sell(
+    market: &mut Market,
+    outcomes: &mut MarketOutcomes,
+    pos: &mut UserPosition,
+    idx: usize,
+    shares: u64,
+    refund: u64,
+) -> Result<()> {
+    if market.pricing_model == PRICING_FPMM {
+        // pool takes `shares` of idx and merges `sets` back into collateral
+        let sets = refund / market.set_price_lamports;
+        outcomes.reserves[idx] = outcomes.reserves[idx]
+            .checked_add(shares)
+            .ok_or(ErrorCode::Overflow)?;
+        for r in outcomes.reserves.iter_mut() {
+            *r = r.checked_sub(sets).ok_or(ErrorCode::NoLiquidity)?;
+        }
+    }
+
+    outcomes.q[idx] = outcomes.q[idx].checked_sub(shares).ok_or(ErrorCode::InsufficientShares)?;
+
+    let entry = pos.entry_mut(idx);
+    entry.shares = entry.shares.checked_sub(shares).ok_or(ErrorCode::NotEnoughShares)?;
+    // the last shares out release whatever basis is left, or it would be orphaned in outcome_cost
+    let basis_out = if entry.shares == 0 {
+        entry.cost_lamports
+    } else {
+        refund.min(entry.cost_lamports)
+    };
+    entry.cost_lamports -= basis_out;
+    if entry.shares == 0 {
+        pos.remove_entry(idx);
+    }
+
+    outcomes.outcome_cost[idx] = outcomes.outcome_cost[idx].saturating_sub(basis_out);
+    Ok(())
+}
+
+/// Credits shares to a position at zero cost basis (e.g. pool shares handed to an LP).
+fn credit_shares(outcomes: &mut MarketOutcomes, pos: &mut UserPosition, idx: usize, shares: u64) -> Result<()> {
+    if shares == 0 {
+        return Ok(());
+    }
+    outcomes.q[idx] = outcomes.q[idx].checked_add(shares).ok_or(ErrorCode::Overflow)?;
+    let entry = pos.entry_mut(idx);
+    entry.shares = entry.shares.checked_add(shares).ok_or(ErrorCode::Overflow)?;
+    Ok(())
+}
+
+/// Initializes a freshly created LP position, or checks an existing one belongs to (market, owner).
+fn init_or_check_lp_position(lp: &mut LiquidityPosition, market_key: Pubkey, owner_key: Pubkey) -> Result<()> {
+    if lp.market == Pubkey::default() {
+        lp.market = market_key;
+        lp.owner = owner_key;
+        lp.lp_shares = 0;
+        lp.fee_debt = 0;
+        lp.fees_owed = 0;
+        lp.net_deposit_lamports = 0;
+        lp.reserves_redeemed = false;
+    } else {
+        require!(lp.market == market_key, ErrorCode::InvalidUserPosition);
+        require!(lp.owner == owner_key, ErrorCode::InvalidUserPosition);
+    }
+    Ok(())
+}
+
+/// Moves LP fees accrued since the last touch into `fees_owed`.
+fn settle_lp_fees(market: &Market, lp: &mut LiquidityPosition) -> Result<()> {
+    let accrued = (lp.lp_shares as u128)
+        .checked_mul(market.lp_fee_per_share)
+        .ok_or(ErrorCode::Overflow)?
+        / LP_FEE_SCALE;
+    let pending = u64::try_from(accrued.saturating_sub(lp.fee_debt))
+        .map_err(|_| error!(ErrorCode::Overflow))?;
+    lp.fees_owed = lp.fees_owed.checked_add(pending).ok_or(ErrorCode::Overflow)?;
+    lp.fee_debt = accrued;
+    Ok(())
+}
+
+/// Re-bases the fee debt after `lp_shares` changed.
+fn reset_lp_fee_debt(market: &Market, lp: &mut LiquidityPosition) -> Result<()> {
+    lp.fee_debt = (lp.lp_shares as u128)
+        .checked_mul(market.lp_fee_per_share)
+        .ok_or(ErrorCode::Overflow)?
+        / LP_FEE_SCALE;
+    Ok(())
+}
+
+/// Grows `account` to at least `new_len` bytes, topping up rent from `payer`.
+fn grow_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &AccountInfo<'info>,
+    system_program: &AccountInfo<'info>,
+    new_len: usize,
+) -> Result<()> {
+    if account.data_len() >= new_len {
+        return Ok(());
+    }
+
+    let rent_needed = Rent::get()?.minimum_balance(new_len);
+    let top_up = rent_needed.saturating_sub(account.lamports());
+    if top_up > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, account.key, top_up),
+            &[payer.clone(), account.clone(), system_program.clone()],
+        )?;
+    }
+
+    account.resize(new_len)?;
+    Ok(())
+}
+
+/// Sells `shares` of a position back to the curve, paying the net refund to `seller_ai`.
+/// Cooldown and the caller's price checks are left to the caller.
+#[allow(clippy::too_many_arguments)]
+fn sell_to_curve(
+    market: &mut Market,
+    outcomes: &mut MarketOutcomes,
+    pos: &mut UserPosition,
+    idx: usize,
+    shares: u64,
+    discount: &FeeDiscount,
+    referral_bps: u16,
+    market_ai: &AccountInfo,
+    seller_ai: &AccountInfo,
+    treasury_ai: &AccountInfo,
+) -> Result<(u64, TradeFees)> {
+    require!(pos.shares_of(idx) >= shares, ErrorCode::NotEnoughShares);
+    require!(outcomes.q[idx] >= shares, ErrorCode::InsufficientShares);
+
+    let refund = curve_sell_refund(market, outcomes, idx, shares)?;
+    require!(refund > 0, ErrorCode::InvalidCost);
+
+    let fees = trade_fees_with(market, refund, discount).with_referral(referral_bps);
+    let net_receive = refund.checked_sub(fees.total()).ok_or(ErrorCode::Overflow)?;
+    require!(market_ai.lamports() >= refund, ErrorCode::InsufficientMarketBalance);
+
+    **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(net_receive);
+    **seller_ai.try_borrow_mut_lamports()? = seller_ai.lamports().saturating_add(net_receive);
+
+    collect_fees(market, market_ai, treasury_ai, &fees)?;
+
+    record_sell(market, outcomes, pos, idx, shares, refund)?;
+    pos.net_cost_lamports = pos
+        .net_cost_lamports
+        .checked_sub(refund as i128)
+        .ok_or(ErrorCode::Overflow)?
+        .max(0);
+
+    Ok((refund, fees))
+}
+
+/// Tops the market up from the insurance fund so paying `owed` leaves its reserves and
+/// rent-exempt minimum intact.
+fn cover_shortfall(
+    fund: &mut Account<InsuranceFund>,
+    market: &Market,
+    market_ai: &AccountInfo,
+    owed: u64,
+    user: Pubkey,
+    kind: ClaimKind,
+) -> Result<()> {
+    let rent_floor = Rent::get()?.minimum_balance(market_ai.data_len());
+    let shortfall = shortfall_lamports(reserved_lamports(market)?, owed, rent_floor, market_ai.lamports())?;
+    if shortfall == 0 {
+        return Ok(());
+    }
+
+    let fund_ai = fund.to_account_info();
+    let rent_floor = Rent::get()?.minimum_balance(fund_ai.data_len());
+    require!(
+        fund_ai.lamports().saturating_sub(rent_floor) >= shortfall,
+        ErrorCode::InsufficientMarketBalance
+    );
+
+    **fund_ai.try_borrow_mut_lamports()? = fund_ai.lamports().saturating_sub(shortfall);
+    **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_add(shortfall);
+    fund.total_drawn_lamports = fund.total_drawn_lamports.saturating_add(shortfall);
+
+    emit!(InsuranceDraw {
+        market: market_ai.key(),
+        user,
+        kind,
+        shortfall_lamports: shortfall,
+    });
+
+    Ok(())
+}
+
+/// Lamports the market is short of paying `owed` while keeping its reserves and rent.
+fn shortfall_lamports(reserved: u64, owed: u64, rent_floor: u64, balance: u64) -> Result<u64> {
+    let needed = reserved
+        .checked_add(owed)
+        .and_then(|v| v.checked_add(rent_floor))
+        .ok_or(ErrorCode::Overflow)?;
+    Ok(needed.saturating_sub(balance))
+}
+
+/// Drops a voided outcome's entry from the position and releases its basis from the
+/// void reserve. Returns the refund owed; the caller moves the lamports.
+fn release_voided_entry(
+    market: &mut Market,
+    outcomes: &mut MarketOutcomes,
+    pos: &mut UserPosition,
+    idx: usize,
+) -> Result<u64> {
+    let (shares, refund) = pos
+        .entry(idx)
+        .map(|e| (e.shares, e.cost_lamports))
+        .unwrap_or((0, 0));
+    require!(refund > 0 || shares > 0, ErrorCode::NothingToRefund);
+    require!(market.void_reserve_lamports >= refund, ErrorCode::InsufficientMarketBalance);
+
+    market.void_reserve_lamports -= refund;
+    outcomes.outcome_cost[idx] = outcomes.outcome_cost[idx].saturating_sub(refund);
+    outcomes.q[idx] = outcomes.q[idx].saturating_sub(shares);
+
+    pos.remove_entry(idx);
+    pos.net_cost_lamports = pos
+        .net_cost_lamports
+        .checked_sub(refund as i128)
+        .ok_or(ErrorCode::Overflow)?
+        .max(0);
+
+    Ok(refund)
+}
+
+/// Like grow_account, but the rent top-up comes from a program-owned account.
+fn grow_account_from(account: &AccountInfo, funder: &AccountInfo, new_len: usize) -> Result<()> {
+    if account.data_len() >= new_len {
+        return Ok(());
+    }
+
+    let rent_needed = Rent::get()?.minimum_balance(new_len);
+    let top_up = rent_needed.saturating_sub(account.lamports());
+    if top_up > 0 {
+        require!(funder.lamports() >= top_up, ErrorCode::InsufficientMarketBalance);
+        **funder.try_borrow_mut_lamports()? = funder.lamports().saturating_sub(top_up);
+        **account.try_borrow_mut_lamports()? = account.lamports().saturating_add(top_up);
+    }
+
+    account.resize(new_len)?;
+    Ok(())
+}
+
+/// Moves shares out of a position into an order; returns the cost basis taken with them.
+/// q is untouched: the shares still exist, the order holds them.
+fn escrow_shares_out(pos: &mut UserPosition, idx: usize, shares: u64) -> Result<u64> {
+    require!(pos.shares_of(idx) >= shares, ErrorCode::NotEnoughShares);
+
+    let entry = pos.entry_mut(idx);
+    // emptying the entry moves its whole basis into escrow
+    let basis = if shares == entry.shares {
+        entry.cost_lamports
+    } else {
+        ((entry.cost_lamports as u128 * shares as u128) / entry.shares as u128) as u64
+    };
+    entry.shares -= shares;
+    entry.cost_lamports -= basis;
+    if entry.shares == 0 {
+        pos.remove_entry(idx);
+    }
+
+    Ok(basis)
+}
+
+/// Returns escrowed shares (and their cost basis) to a position.
+fn escrow_shares_in(pos: &mut UserPosition, idx: usize, shares: u64, cost_lamports: u64) {
+    let entry = pos.entry_mut(idx);
+    entry.shares = entry.shares.saturating_add(shares);
+    entry.cost_lamports = entry.cost_lamports.saturating_add(cost_lamports);
+}
+
+/* ============================== SWAP INNER ============================== */
+
+fn swap_inner(
+    ctx: Context<Trade>,
+    from_outcome: u8,
+    to_outcome: u8,
+    shares: u64,
+    min_shares_out: u64,
+) -> Result<()> {
+    let trader_key = ctx.accounts.trader.key();
+    let market_key = ctx.accounts.market.key();
+
+    let trader_ai = ctx.accounts.trader.to_account_info();
+    let market_ai = ctx.accounts.market.to_account_info();
+    let pos_ai = ctx.accounts.user_position.to_account_info();
+    let system_ai = ctx.accounts.system_program.to_account_info();
+    let treasury_ai = ctx.accounts.treasury.to_account_info();
+
+    let market = &mut ctx.accounts.market;
+    let outcomes = &mut ctx.accounts.market_outcomes;
+    let pos = &mut ctx.accounts.user_position;
+
+    let now = require_market_tradable(market)?;
+    require!(market.batch_seconds == 0, ErrorCode::BatchAuctionOnly);
+    require!(!market.commit_reveal, ErrorCode::CommitRevealRequired);
+
+    accrue_twap(market, outcomes, now)?;
+
+    require!(shares > 0, ErrorCode::InvalidShares);
+    require!(shares <= market.max_trade_shares, ErrorCode::TradeTooLarge);
+
+    init_or_check_position(pos, market_key, trader_key)?;
+
+    // one swap = one trade for cooldown purposes
+    if market.cooldown_seconds > 0 && pos.last_trade_ts > 0 {
+        require!(
+            now - pos.last_trade_ts >= market.cooldown_seconds,
+            ErrorCode::CooldownActive
+        );
+    }
+
+    let from = from_outcome as usize;
+    let to = to_outcome as usize;
+    require!(from < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+    require!(to < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+    require!(from != to, ErrorCode::InvalidOutcomeIndex);
+    require!(!market.is_voided(from), ErrorCode::OutcomeVoided);
+    require!(!market.is_voided(to), ErrorCode::OutcomeVoided);
+
+    let to_before = spot_price_lamports(market, outcomes, to).ok();
+
+    // sell leg
+    require!(pos.shares_of(from) >= shares, ErrorCode::NotEnoughShares);
+    require!(outcomes.q[from] >= shares, ErrorCode::InsufficientShares);
+
+    let refund = curve_sell_refund(market, outcomes, from, shares)?;
+    require!(refund > 0, ErrorCode::InvalidCost);
+    require!(market_ai.lamports() >= refund, ErrorCode::InsufficientMarketBalance);
+
+    // fees once, on the proceeds routed through the swap
+    let referral_bps = referral_share(&ctx.accounts.referrer, trader_key)?;
+    let discount = fee_discount(market, &ctx.accounts.protocol_config, &mut ctx.accounts.trader_stats, now);
+    let fees = trade_fees_with(market, refund, &discount).with_referral(referral_bps);
+    let budget = refund
+        .checked_sub(fees.total()).ok_or(ErrorCode::Overflow)?;
+
+    record_sell(market, outcomes, pos, from, shares, refund)?;
+
+    // buy leg, quoted after the sell leg moved the curve
+    let (shares_out, cost) =
+        shares_for_budget(market, outcomes, to, budget, market.max_trade_shares)?;
+    require!(shares_out > 0, ErrorCode::InvalidShares);
+    require!(shares_out >= min_shares_out, ErrorCode::SlippageExceeded);
+
+    let dust = budget.checked_sub(cost).ok_or(ErrorCode::Overflow)?;
+
+    collect_fees(market, &market_ai, &treasury_ai, &fees)?;
+    pay_referrer(&mut ctx.accounts.referrer, &market_ai, &fees)?;
+
+    // unspent remainder goes back to the trader
+    if dust > 0 {
+        **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(dust);
+        **trader_ai.try_borrow_mut_lamports()? = trader_ai.lamports().saturating_add(dust);
+    }
+
+    record_buy(market, outcomes, pos, to, shares_out, cost)?;
+
+    record_candle(&ctx.accounts.price_history, market, outcomes, now, &[(from, refund), (to, cost)])?;
+    record_volume(&mut ctx.accounts.trader_stats, refund.saturating_add(cost), &fees, now);
+    track_volatility(market, outcomes, to, to_before, now);
+
+    pos.last_trade_ts = now;
+    pos.net_cost_lamports = pos
+        .net_cost_lamports
+        .checked_sub(refund as i128)
+        .ok_or(ErrorCode::Overflow)?
+        .max(0)
+        .checked_add(cost as i128)
+        .ok_or(ErrorCode::Overflow)?;
+
+    enforce_position_cap(market, outcomes, pos, to)?;
+
+    let needed = 8 + pos.try_to_vec()?.len();
+    grow_account(&pos_ai, &trader_ai, &system_ai, needed)?;
+
+    emit!(TradeExecuted {
+        market: market.key(),
+        user: trader_key,
+        is_buy: false,
+        outcome_index: from_outcome,
+        shares,
+        amount_lamports: refund,
+        platform_fee_lamports: fees.platform,
+        creator_fee_lamports: fees.creator,
+        lp_fee_lamports: fees.lp,
+        referrer_fee_lamports: fees.referrer,
+        platform_fee_bps: fees.platform_bps as u16,
+    });
+
+    emit!(TradeExecuted {
+        market: market.key(),
+        user: trader_key,
+        is_buy: true,
+        outcome_index: to_outcome,
+        shares: shares_out,
+        amount_lamports: cost,
+        platform_fee_lamports: 0,
+        creator_fee_lamports: 0,
+        lp_fee_lamports: 0,
+        referrer_fee_lamports: 0,
+        platform_fee_bps: 0,
+    });
+
+    Ok(())
+}
+
+/* ============================== BATCH INNER ============================== */
+
+fn batch_inner(
+    ctx: Context<Trade>,
+    legs: Vec<TradeLeg>,
+    max_pay_lamports: u64,
+    min_receive_lamports: u64,
+) -> Result<()> {
+    let trader_key = ctx.accounts.trader.key();
+    let market_key = ctx.accounts.market.key();
+
+    let trader_ai = ctx.accounts.trader.to_account_info();
+    let market_ai = ctx.accounts.market.to_account_info();
+    let pos_ai = ctx.accounts.user_position.to_account_info();
+    let system_ai = ctx.accounts.system_program.to_account_info();
+    let treasury_ai = ctx.accounts.treasury.to_account_info();
+
+    let market = &mut ctx.accounts.market;
+    let outcomes = &mut ctx.accounts.market_outcomes;
+    let pos = &mut ctx.accounts.user_position;
+
+    let now = require_market_tradable(market)?;
+    require!(market.batch_seconds == 0, ErrorCode::BatchAuctionOnly);
+    require!(!market.commit_reveal, ErrorCode::CommitRevealRequired);
+
+    accrue_twap(market, outcomes, now)?;
+
+    require!(!legs.is_empty() && legs.len() <= MAX_BATCH_LEGS, ErrorCode::InvalidBatch);
+
+    init_or_check_position(pos, market_key, trader_key)?;
+
+    // one batch = one trade for cooldown purposes
+    if market.cooldown_seconds > 0 && pos.last_trade_ts > 0 {
+        require!(
+            now - pos.last_trade_ts >= market.cooldown_seconds,
+            ErrorCode::CooldownActive
+        );
+    }
+
+    // trader inflow (buys incl. fees) and outflow (sells net of fees)
+    let mut pay_in: u64 = 0;
+    let mut pay_out: u64 = 0;
+    let mut fees_total = TradeFees::default();
+    let mut traded: Vec<(usize, u64)> = Vec::with_capacity(legs.len());
+    let mut net_cost = pos.net_cost_lamports;
+    let referral_bps = referral_share(&ctx.accounts.referrer, trader_key)?;
+    let discount = fee_discount(market, &ctx.accounts.protocol_config, &mut ctx.accounts.trader_stats, now);
+
+    for leg in legs.iter() {
+        let idx = leg.outcome_index as usize;
+        require!(idx < market.outcome_count as usize, ErrorCode::InvalidOutcomeIndex);
+        require!(!market.is_voided(idx), ErrorCode::OutcomeVoided);
+        require!(leg.shares > 0, ErrorCode::InvalidShares);
+        require!(leg.shares <= market.max_trade_shares, ErrorCode::TradeTooLarge);
+
+        let spot_before = spot_price_lamports(market, outcomes, idx).ok();
+        let amount = if leg.is_buy {
+            curve_buy_cost(market, outcomes, idx, leg.shares)?
+        } else {
+            require!(pos.shares_of(idx) >= leg.shares, ErrorCode::NotEnoughShares);
+            require!(outcomes.q[idx] >= leg.shares, ErrorCode::InsufficientShares);
+            curve_sell_refund(market, outcomes, idx, leg.shares)?
+        };
+        require!(amount > 0, ErrorCode::InvalidCost);
+
+        let fees = trade_fees_with(market, amount, &discount).with_referral(referral_bps);
+
+        if leg.is_buy {
+            pay_in = pay_in
+                .checked_add(amount.checked_add(fees.total()).ok_or(ErrorCode::Overflow)?)
+                .ok_or(ErrorCode::Overflow)?;
+            record_buy(market, outcomes, pos, idx, leg.shares, amount)?;
+            net_cost = net_cost.checked_add(amount as i128).ok_or(ErrorCode::Overflow)?;
+        } else {
+            pay_out = pay_out
+                .checked_add(amount.checked_sub(fees.total()).ok_or(ErrorCode::Overflow)?)
+                .ok_or(ErrorCode::Overflow)?;
+            record_sell(market, outcomes, pos, idx, leg.shares, amount)?;
+            net_cost = net_cost
+                .checked_sub(amount as i128)
+                .ok_or(ErrorCode::Overflow)?
+                .max(0);
+        }
+
+        fees_total.accumulate(&fees);
+        traded.push((idx, amount));
+        record_volume(&mut ctx.accounts.trader_stats, amount, &fees, now);
+        track_volatility(market, outcomes, idx, spot_before, now);
+
+        emit!(TradeExecuted {
+            market: market_key,
+            user: trader_key,
+            is_buy: leg.is_buy,
+            outcome_index: leg.outcome_index,
+            shares: leg.shares,
+            amount_lamports: amount,
+            platform_fee_lamports: fees.platform,
+            creator_fee_lamports: fees.creator,
+            lp_fee_lamports: fees.lp,
+            referrer_fee_lamports: fees.referrer,
+            platform_fee_bps: fees.platform_bps as u16,
+        });
+    }
+
+    // single settlement for the net amount
+    if pay_in >= pay_out {
+        let net_pay = pay_in - pay_out;
+        require!(net_pay <= max_pay_lamports, ErrorCode::SlippageExceeded);
+        if net_pay > 0 {
+            invoke(
+                &system_instruction::transfer(&trader_key, &market_key, net_pay),
+                &[trader_ai.clone(), market_ai.clone(), system_ai.clone()],
+            )?;
+        }
+    } else {
+        let net_receive = pay_out - pay_in;
+        require!(net_receive >= min_receive_lamports, ErrorCode::SlippageExceeded);
+        require!(
+            market_ai.lamports()
+                >= net_receive
+                    .saturating_add(fees_total.platform)
+                    .saturating_add(fees_total.referrer),
+            ErrorCode::InsufficientMarketBalance
+        );
+        **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(net_receive);
+        **trader_ai.try_borrow_mut_lamports()? = trader_ai.lamports().saturating_add(net_receive);
+    }
+
+    collect_fees(market, &market_ai, &treasury_ai, &fees_total)?;
+    pay_referrer(&mut ctx.accounts.referrer, &market_ai, &fees_total)?;
+    record_candle(&ctx.accounts.price_history, market, outcomes, now, &traded)?;
+
+    pos.net_cost_lamports = net_cost;
+    pos.last_trade_ts = now;
+
+    // caps are checked on the final position, not per leg
+    for leg in legs.iter().filter(|l| l.is_buy) {
+        enforce_position_cap(market, outcomes, pos, leg.outcome_index as usize)?;
+    }
+
+    let needed = 8 + pos.try_to_vec()?.len();
+    grow_account(&pos_ai, &trader_ai, &system_ai, needed)?;
+
+    Ok(())
+}
+
+/* ============================== BATCH AUCTION ============================== */
+
+fn clear_batch_inner<'info>(ctx: Context<'_, '_, 'info, 'info, ClearBatch<'info>>) -> Result<()> {
+    let market_key = ctx.accounts.market.key();
+
+    let market_ai = ctx.accounts.market.to_account_info();
+    let queue_ai = ctx.accounts.order_queue.to_account_info();
+    let treasury_ai = ctx.accounts.treasury.to_account_info();
+
+    let market = &mut ctx.accounts.market;
+    let outcomes = &mut ctx.accounts.market_outcomes;
+    let queue = &mut ctx.accounts.order_queue;
+
+    require!(market.batch_seconds > 0, ErrorCode::InvalidState);
+    require!(!queue.orders.is_empty(), ErrorCode::EmptyBatch);
+
+    // a market that stopped trading cancels the batch instead of filling it
+    let now = Clock::get()?.unix_timestamp;
+    let tradable = require_market_tradable(market).is_ok();
+    require!(now >= queue.batch_end_ts || !tradable, ErrorCode::BatchStillOpen);
+
+    if tradable {
+        accrue_twap(market, outcomes, now)?;
+    }
+
+    let mut traders: Vec<Pubkey> = Vec::new();
+    for o in queue.orders.iter() {
+        if !traders.contains(&o.trader) {
+            traders.push(o.trader);
+        }
+    }
+    require!(ctx.remaining_accounts.len() >= traders.len() * 2, ErrorCode::InvalidBatch);
+
+    let mut positions: Vec<Account<'info, UserPosition>> = Vec::with_capacity(traders.len());
+    for (t, trader) in traders.iter().enumerate() {
+        let pos = Account::<UserPosition>::try_from(&ctx.remaining_accounts[2 * t])?;
+        require!(pos.market == market_key, ErrorCode::InvalidUserPosition);
+        require_keys_eq!(pos.user, *trader, ErrorCode::InvalidUserPosition);
+        require_keys_eq!(ctx.remaining_accounts[2 * t + 1].key(), *trader, ErrorCode::InvalidUserPosition);
+        positions.push(pos);
+    }
+
+    let mut stats: Vec<Account<'info, TraderStats>> = Vec::new();
+    let mut stats_of: Vec<Option<usize>> = vec![None; traders.len()];
+    for ai in ctx.remaining_accounts[traders.len() * 2..].iter() {
+        let s = Account::<TraderStats>::try_from(ai)?;
+        let t = traders.iter().position(|x| *x == s.trader).ok_or(ErrorCode::InvalidBatch)?;
+        require!(stats_of[t].is_none(), ErrorCode::InvalidBatch);
+        stats_of[t] = Some(stats.len());
+        stats.push(s);
+    }
+
+    let orders = std::mem::take(&mut queue.orders);
+    let batch_id = queue.batch_id;
+    let mut settled = vec![false; orders.len()];
+
+    let mut traded: Vec<(usize, u64)> = Vec::new();
+
+    // escrowed sell shares go back to their positions; whatever doesn't fill stays there
+    for order in orders.iter().filter(|o| !o.is_buy) {
+        let t = traders.iter().position(|x| *x == order.trader).ok_or(ErrorCode::InvalidBatch)?;
+        let idx = order.outcome_index as usize;
+        escrow_shares_in(&mut positions[t], idx, order.shares, order.escrow_cost_lamports);
+    }
+
+    for idx in 0..market.outcome_count as usize {
+        let members: Vec<usize> = (0..orders.len())
+            .filter(|i| orders[*i].outcome_index as usize == idx)
+            .collect();
+        if members.is_empty() || !tradable {
+            continue;
+        }
+
+        let sizes = batch_order_sizes(market, outcomes, &orders, &members, &traders, &positions)?;
+        let (fills, (amount, net)) = batch_clear_outcome(market, outcomes, idx, &orders, &members, &sizes)?;
+
+        let mut buy_shares = 0u64;
+        let mut sell_shares = 0u64;
+
+        // buys first so q never dips below the sells settling after them
+        for pass_buys in [true, false] {
+            for (k, i) in members.iter().enumerate() {
+                let order = &orders[*i];
+                if order.is_buy != pass_buys || fills[k] == 0 {
+                    continue;
+                }
+
+                let t = traders.iter().position(|x| *x == order.trader).ok_or(ErrorCode::InvalidBatch)?;
+                let trader_ai = &ctx.remaining_accounts[2 * t + 1];
+                let pos = &mut positions[t];
+                let fill = fills[k];
+
+                // uniform price: amount / net per share (buyers round up, sellers down)
+                let notional = (fill as u128)
+                    .checked_mul(amount as u128)
+                    .ok_or(ErrorCode::Overflow)?;
+                let value = if order.is_buy {
+                    notional.div_ceil(net as u128)
+                } else {
+                    notional / net as u128
+                };
+                let value = u64::try_from(value).map_err(|_| error!(ErrorCode::Overflow))?;
+                let fees = trade_fees_with(market, value, &FeeDiscount::at(market, now));
+
+                if order.is_buy {
+                    let total = value.checked_add(fees.total()).ok_or(ErrorCode::Overflow)?;
+                    let refund = order
+                        .escrow_lamports
+                        .checked_sub(total)
+                        .ok_or(ErrorCode::SlippageExceeded)?;
+
+                    **queue_ai.try_borrow_mut_lamports()? = queue_ai.lamports().saturating_sub(order.escrow_lamports);
+                    **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_add(total);
+                    **trader_ai.try_borrow_mut_lamports()? = trader_ai.lamports().saturating_add(refund);
+
+                    collect_fees(market, &market_ai, &treasury_ai, &fees)?;
+                    record_buy(market, outcomes, pos, idx, fill, value)?;
+                    pos.net_cost_lamports = pos
+                        .net_cost_lamports
+                        .checked_add(value as i128)
+                        .ok_or(ErrorCode::Overflow)?;
+                    buy_shares += fill;
+                    traded.push((idx, value));
+                    settled[*i] = true;
+                } else {
+                    let net_refund = value.checked_sub(fees.total()).ok_or(ErrorCode::Overflow)?;
+                    require!(market_ai.lamports() >= value, ErrorCode::InsufficientMarketBalance);
+
+                    **market_ai.try_borrow_mut_lamports()? = market_ai.lamports().saturating_sub(net_refund);
+                    **trader_ai.try_borrow_mut_lamports()? = trader_ai.lamports().saturating_add(net_refund);
+
+                    collect_fees(market, &market_ai, &treasury_ai, &fees)?;
+                    record_sell(market, outcomes, pos, idx, fill, value)?;
+                    pos.net_cost_lamports = pos
+                        .net_cost_lamports
+                        .checked_sub(value as i128)
+                        .ok_or(ErrorCode::Overflow)?
+                        .max(0);
+                    sell_shares += fill;
+                    traded.push((idx, value));
+                }
+                pos.last_trade_ts = now;
+                if let Some(s) = stats_of[t] {
+                    stats[s].record(now, value, fees.rebate);
+                }
+
+                emit!(TradeExecuted {
+                    market: market_key,
+                    user: order.trader,
+                    is_buy: order.is_buy,
+                    outcome_index: order.outcome_index,
+                    shares: fill,
+                    amount_lamports: value,
+                    platform_fee_lamports: fees.platform,
+                    creator_fee_lamports: fees.creator,
+                    lp_fee_lamports: fees.lp,
+                    referrer_fee_lamports: fees.referrer,
+                    platform_fee_bps: fees.platform_bps as u16,
+                });
+            }
+        }
+
+        if buy_shares > 0 || sell_shares > 0 {
+            emit!(BatchCleared {
+                market: market_key,
+                batch_id,
+                outcome_index: idx as u8,
+                clearing_price_lamports: amount / net,
+                buy_shares,
+                sell_shares,
+            });
+        }
+    }
+
+    if !traded.is_empty() {
+        record_candle(&ctx.accounts.price_history, market, outcomes, now, &traded)?;
+    }
+
+    // unfilled buys get their whole escrow back
+    for (i, order) in orders.iter().enumerate() {
+        if !order.is_buy || settled[i] {
+            continue;
+        }
+        let t = traders.iter().position(|x| *x == order.trader).ok_or(ErrorCode::InvalidBatch)?;
+        let trader_ai = &ctx.remaining_accounts[2 * t + 1];
+
+        **queue_ai.try_borrow_mut_lamports()? = queue_ai.lamports().saturating_sub(order.escrow_lamports);
+        **trader_ai.try_borrow_mut_lamports()? = trader_ai.lamports().saturating_add(order.escrow_lamports);
+    }
+
+    queue.batch_id = batch_id.checked_add(1).ok_or(ErrorCode::Overflow)?;
+    queue.batch_end_ts = 0;
+
+    for pos in positions.iter() {
+        pos.exit(&crate::ID)?;
+    }
+    for s in stats.iter() {
+        s.exit(&crate::ID)?;
+    }
+
+    Ok(())
+}
+
+/// Fillable size per order: sells up to the trader's remaining holdings, buys up
+/// to the position cap headroom (shared across a trader's orders in the batch).
+fn batch_order_sizes(
+    market: &Market,
+    outcomes: &MarketOutcomes,
+    orders: &[QueuedOrder],
+    members: &[usize],
+    traders: &[Pubkey],
+    positions: &[Account<UserPosition>],
+) -> Result<Vec<u64>> {
+    let idx = orders[members[0]].outcome_index as usize;
+    let total: u64 = outcomes.q.iter().sum();
+
+    let mut held: Vec<u64> = positions.iter().map(|p| p.shares_of(idx)).collect();
+    let mut bought: Vec<u64> = vec![0; positions.len()];
+
+    let mut sizes = Vec::with_capacity(members.len());
+    for i in members.iter() {
+        let order = &orders[*i];
+        let t = traders.iter().position(|x| *x == order.trader).ok_or(ErrorCode::InvalidBatch)?;
+
+        let size = if order.is_buy {
+            let after_total = total.saturating_add(order.shares);
+            let cap = if market.max_position_bps >= 10_000 || after_total < market.max_trade_shares {
+                u64::MAX
+            } else {
+                ((after_total as u128 * market.max_position_bps as u128) / 10_000) as u64
+            };
+            let size = order
+                .shares
+                .min(cap.saturating_sub(held[t]).saturating_sub(bought[t]));
+            bought[t] += size;
+            size
+        } else {
+            let size = order.shares.min(held[t]);
+            held[t] -= size;
+            size
+        };
+        sizes.push(size);
+    }
+
+    Ok(sizes)
+}
+
+/// Uniform batch price for `buy` vs `sell` shares on one outcome, as (amount, shares):
+/// the curve only sees the net flow; a balanced batch trades at the spot price.
+fn batch_price(market: &Market, outcomes: &MarketOutcomes, idx: usize, buy: u64, sell: u64) -> Result<(u64, u64)> {
+    if buy > sell {
+        let net = buy - sell;
+        Ok((curve_buy_cost(market, outcomes, idx, net)?, net))
+    } else if sell > buy {
+        let net = sell - buy;
+        require!(outcomes.q[idx] >= net, ErrorCode::InsufficientShares);
+        Ok((curve_sell_refund(market, outcomes, idx, net)?, net))
+    } else {
+        Ok((curve_buy_cost(market, outcomes, idx, 1)?, 1))
+    }
+}
+
+fn batch_limit_ok(is_buy: bool, limit: u64, (amount, net): (u64, u64)) -> bool {
+    let at_limit = limit as u128 * net as u128;
+    if is_buy {
+        at_limit >= amount as u128
+    } else {
+        at_limit <= amount as u128
+    }
+}
+
+/// Fills per member order and the uniform price. Orders whose limit the price
+/// violates drop out until stable; the best excluded limit on each side is then
+/// filled pro rata up to the quantity that keeps the price at that limit.
+fn batch_clear_outcome(
+    market: &Market,
+    outcomes: &MarketOutcomes,
+    idx: usize,
+    orders: &[QueuedOrder],
+    members: &[usize],
+    sizes: &[u64],
+) -> Result<(Vec<u64>, (u64, u64))> {
+    let m = members.len();
+    let order = |k: usize| &orders[members[k]];
+
+    let totals = |fills: &[u64]| -> (u64, u64) {
+        let mut buy = 0u64;
+        let mut sell = 0u64;
+        for (k, f) in fills.iter().enumerate() {
+            if order(k).is_buy {
+                buy += f;
+            } else {
+                sell += f;
+            }
+        }
+        (buy, sell)
+    };
+
+    let mut fills: Vec<u64> = sizes.to_vec();
+
+    // exclusion only ever shrinks the set, so this settles within m rounds
+    for _ in 0..=m {
+        let (buy, sell) = totals(&fills);
+        let price = batch_price(market, outcomes, idx, buy, sell)?;
+
+        let mut changed = false;
+        for (k, fill) in fills.iter_mut().enumerate() {
+            if *fill > 0 && !batch_limit_ok(order(k).is_buy, order(k).limit_price_lamports, price) {
+                *fill = 0;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    for side_buy in [true, false] {
+        // marginal group: best excluded limit on this side
+        let excluded: Vec<usize> = (0..m)
+            .filter(|k| order(*k).is_buy == side_buy && fills[*k] == 0 && sizes[*k] > 0)
+            .collect();
+        let marginal = if side_buy {
+            excluded.iter().map(|k| order(*k).limit_price_lamports).max()
+        } else {
+            excluded.iter().map(|k| order(*k).limit_price_lamports).min()
+        };
+        let Some(limit) = marginal else { continue };
+
+        let group: Vec<usize> = excluded
+            .into_iter()
+            .filter(|k| order(*k).limit_price_lamports == limit)
+            .collect();
+        let group_total: u64 = group.iter().map(|k| sizes[*k]).sum();
+
+        let (buy, sell) = totals(&fills);
+        let (mut lo, mut hi) = (0u64, group_total);
+        while lo < hi {
+            let mid = lo + (hi - lo).div_ceil(2);
+            let price = if side_buy {
+                batch_price(market, outcomes, idx, buy + mid, sell)?
+            } else {
+                batch_price(market, outcomes, idx, buy, sell + mid)?
+            };
+            if batch_limit_ok(side_buy, limit, price) {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+
+        if lo > 0 {
+            for k in group {
+                fills[k] = ((sizes[k] as u128 * lo as u128) / group_total as u128) as u64;
+            }
+        }
+    }
+
+    let (buy, sell) = totals(&fills);
+    let price = batch_price(market, outcomes, idx, buy, sell)?;
+
+    Ok((fills, price))
+}
+
+/* ============================== ANTI-MANIP ============================== */
+
+fn enforce_position_cap(
+    market: &Market,
+    outcomes: &MarketOutcomes,
+    pos: &UserPosition,
+    idx: usize,
+) -> Result<()> {
+    let max_bps = market.max_position_bps as u64;
+
+    if max_bps >= 10_000 {
+        return Ok(());
+    }
+
+    let mut total: u128 = 0;
+    for q in outcomes.q.iter() {
+        total = total
+            .checked_add(*q as u128)
+            .ok_or(ErrorCode::Overflow)?;
+    }
+
+    if total < market.max_trade_shares as u128 {
+        return Ok(());
+    }
+
+    let user: u128 = pos.shares_of(idx) as u128;
+
+    let max_allowed: u128 = total
+        .checked_mul(max_bps as u128)
+        .ok_or(ErrorCode::Overflow)?
+        .checked_div(10_000u128)
+        .ok_or(ErrorCode::Overflow)?;
+
+    require!(user <= max_allowed, ErrorCode::PositionCapExceeded);
+    Ok(())
+}
+
+/* ============================== ACCOUNTS ============================== */
+
+#[derive(Accounts)]
+#[instruction(resolution_time: i64, outcome_names: Vec<String>)]
+pub struct CreateMarket<'info> {
+    #[account(init, payer = creator, space = Market::SPACE)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = creator,
+        space = MarketOutcomes::space_for(&outcome_names),
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct Trade<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
+    #[account(
+        init_if_needed,
+        payer = trader,
+        space = UserPosition::SPACE,
+        seeds = [b"user_position", market.key().as_ref(), trader.key().as_ref()],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    /// platform treasury (fee accrual)
+    #[account(mut, seeds = [b"treasury"], bump)]
+    pub treasury: Account<'info, Treasury>,
+
+    /// CHECK: creator wallet (fee receiver)
+    #[account(mut, address = market.creator)]
+    pub creator: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    #[account(
+        mut,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump
+    )]
+    pub price_history: Option<AccountLoader<'info, PriceHistory>>,
+
+    /// registered referrer credited with part of the platform fee
+    #[account(mut)]
+    pub referrer: Option<Account<'info, Referrer>>,
+
+    #[account(seeds = [b"protocol_config"], bump)]
+    pub protocol_config: Option<Account<'info, ProtocolConfig>>,
+
+    /// trader's rolling volume; without it the base platform fee applies
+    #[account(
+        mut,
+        seeds = [b"trader_stats", trader.key().as_ref()],
+        bump
+    )]
+    pub trader_stats: Option<Account<'info, TraderStats>>,
+}
+
+#[derive(Accounts)]
+pub struct CompleteSet<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
+    #[account(
+        init_if_needed,
+        payer = trader,
+        space = UserPosition::SPACE,
+        seeds = [b"user_position", market.key().as_ref(), trader.key().as_ref()],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    #[account(
+        mut,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump
+    )]
+    pub price_history: Option<AccountLoader<'info, PriceHistory>>,
+}
+
+#[derive(Accounts)]
+pub struct Liquidity<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
+    #[account(
+        init_if_needed,
+        payer = provider,
+        space = LiquidityPosition::SPACE,
+        seeds = [b"lp_position", market.key().as_ref(), provider.key().as_ref()],
+        bump
+    )]
+    pub liquidity_position: Account<'info, LiquidityPosition>,
+
+    // receives outcome shares the pool hands back
+    #[account(
+        init_if_needed,
+        payer = provider,
+        space = UserPosition::SPACE,
+        seeds = [b"user_position", market.key().as_ref(), provider.key().as_ref()],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub provider: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    #[account(
+        mut,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump
+    )]
+    pub price_history: Option<AccountLoader<'info, PriceHistory>>,
+}
+
+#[derive(Accounts)]
+pub struct Subsidy<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
+    #[account(
+        init_if_needed,
+        payer = provider,
+        space = LiquidityPosition::SPACE,
+        seeds = [b"lp_position", market.key().as_ref(), provider.key().as_ref()],
+        bump
+    )]
+    pub liquidity_position: Account<'info, LiquidityPosition>,
+
+    #[account(mut)]
+    pub provider: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimLpFees<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"lp_position", market.key().as_ref(), provider.key().as_ref()],
+        bump
+    )]
+    pub liquidity_position: Account<'info, LiquidityPosition>,
+
+    #[account(mut)]
+    pub provider: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ProposeResolution<'info> {
+    #[account(mut, has_one = creator)]
+    pub market: Account<'info, Market>,
+    pub creator: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct Dispute<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+    pub user: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AdminResolve<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut, address = ADMIN_AUTHORITY)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AdminFinalize<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
+    #[account(mut, address = ADMIN_AUTHORITY)]
+    pub admin: Signer<'info>,
+
+    /// platform treasury (receives a graduated market's leftover subsidy)
+    #[account(mut, seeds = [b"treasury"], bump)]
+    pub treasury: Account<'info, Treasury>,
+}
+
+#[derive(Accounts)]
+pub struct AdminVoidOutcome<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
+    #[account(mut, address = ADMIN_AUTHORITY)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct AddOutcome<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
+    /// CHECK: price history PDA, read only to see whether the market is charted
+    #[account(seeds = [b"price_history", market.key().as_ref()], bump)]
+    pub price_history: UncheckedAccount<'info>,
+
+    #[account(mut)]
+    pub authority: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimCreatorFees<'info> {
+    #[account(mut, has_one = creator)]
+    pub market: Account<'info, Market>,
+
+    #[account(mut)]
+    pub creator: Signer<'info>,
+
+    /// platform treasury (receives an unearned graduation bonus)
+    #[account(mut, seeds = [b"treasury"], bump)]
+    pub treasury: Account<'info, Treasury>,
+}
+
+#[derive(Accounts)]
+#[instruction(order_id: u64)]
+pub struct PlaceLimitOrder<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = LimitOrder::SPACE,
+        seeds = [b"limit_order", market.key().as_ref(), owner.key().as_ref(), &order_id.to_le_bytes()],
+        bump
+    )]
+    pub limit_order: Account<'info, LimitOrder>,
+
+    #[account(
+        init_if_needed,
+        payer = owner,
+        space = UserPosition::SPACE,
+        seeds = [b"user_position", market.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+
+    /// registered referrer, recorded on the order and credited at fill
+    pub referrer: Option<Account<'info, Referrer>>,
+}
 
 #[derive(Accounts)]
-pub struct Trade<'info> {
+pub struct FillOrder<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
+    #[account(mut, has_one = market, has_one = owner)]
+    pub limit_order: Account<'info, LimitOrder>,
+
+    #[account(
+        mut,
+        seeds = [b"user_position", market.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    /// CHECK: order owner (receives sell proceeds and leftover escrow)
+    #[account(mut)]
+    pub owner: UncheckedAccount<'info>,
+
+    /// platform treasury (fee accrual)
+    #[account(mut, seeds = [b"treasury"], bump)]
+    pub treasury: Account<'info, Treasury>,
+
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump
+    )]
+    pub price_history: Option<AccountLoader<'info, PriceHistory>>,
+
+    /// owner's rolling volume (credited with the fill; sets the fee tier)
+    #[account(
+        mut,
+        seeds = [b"trader_stats", owner.key().as_ref()],
+        bump
+    )]
+    pub trader_stats: Option<Account<'info, TraderStats>>,
+
+    #[account(seeds = [b"protocol_config"], bump)]
+    pub protocol_config: Option<Account<'info, ProtocolConfig>>,
+
+    /// must match the referrer recorded on the order
+    #[account(mut)]
+    pub referrer: Option<Account<'info, Referrer>>,
+}
+
+#[derive(Accounts)]
+pub struct CancelLimitOrder<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(mut, has_one = market, has_one = owner, close = owner)]
+    pub limit_order: Account<'info, LimitOrder>,
+
+    #[account(
+        mut,
+        seeds = [b"user_position", market.key().as_ref(), owner.key().as_ref()],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[instruction(trigger_id: u64)]
+pub struct PlaceTriggerOrder<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(
+        seeds = [b"user_position", market.key().as_ref(), owner.key().as_ref()],
+        bump,
+        constraint = user_position.user == owner.key() @ ErrorCode::InvalidUserPosition
+    )]
+    pub user_position: Account<'info, UserPosition>,
+
+    #[account(
+        init,
+        payer = owner,
+        space = TriggerOrder::SPACE,
+        seeds = [b"trigger_order", user_position.key().as_ref(), &trigger_id.to_le_bytes()],
+        bump
+    )]
+    pub trigger_order: Account<'info, TriggerOrder>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ExecuteTrigger<'info> {
+    #[account(mut)]
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
+    #[account(mut, has_one = market, has_one = owner, has_one = user_position)]
+    pub trigger_order: Account<'info, TriggerOrder>,
+
+    #[account(mut)]
+    pub user_position: Account<'info, UserPosition>,
+
+    /// CHECK: trigger owner (receives proceeds and the trigger's rent)
+    #[account(mut)]
+    pub owner: UncheckedAccount<'info>,
+
+    /// platform treasury (fee accrual)
+    #[account(mut, seeds = [b"treasury"], bump)]
+    pub treasury: Account<'info, Treasury>,
+
+    #[account(mut)]
+    pub keeper: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump
+    )]
+    pub price_history: Option<AccountLoader<'info, PriceHistory>>,
+
+    /// owner's rolling volume (credited with the fill)
+    #[account(
+        mut,
+        seeds = [b"trader_stats", owner.key().as_ref()],
+        bump
+    )]
+    pub trader_stats: Option<Account<'info, TraderStats>>,
+}
+
+#[derive(Accounts)]
+pub struct CancelTriggerOrder<'info> {
+    #[account(mut, has_one = owner, close = owner)]
+    pub trigger_order: Account<'info, TriggerOrder>,
+
+    #[account(mut)]
+    pub owner: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct RegisterReferrer<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = Referrer::SPACE,
+        seeds = [b"referrer", wallet.key().as_ref()],
+        bump
+    )]
+    pub referrer: Account<'info, Referrer>,
+
+    /// CHECK: referrer's payout wallet
+    pub wallet: UncheckedAccount<'info>,
+
+    #[account(mut, address = ADMIN_AUTHORITY)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetReferrerShare<'info> {
+    #[account(
+        mut,
+        seeds = [b"referrer", referrer.wallet.as_ref()],
+        bump
+    )]
+    pub referrer: Account<'info, Referrer>,
+
+    #[account(address = ADMIN_AUTHORITY)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct ClaimReferrerFees<'info> {
+    #[account(
+        mut,
+        seeds = [b"referrer", wallet.key().as_ref()],
+        bump,
+        has_one = wallet
+    )]
+    pub referrer: Account<'info, Referrer>,
+
+    #[account(mut)]
+    pub wallet: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitTreasury<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = Treasury::SPACE,
+        seeds = [b"treasury"],
+        bump
+    )]
+    pub treasury: Account<'info, Treasury>,
+
+    #[account(mut, address = ADMIN_AUTHORITY)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct TreasuryAdmin<'info> {
+    #[account(mut, seeds = [b"treasury"], bump, has_one = authority)]
+    pub treasury: Account<'info, Treasury>,
+
+    pub authority: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct DistributeTreasury<'info> {
+    #[account(mut, seeds = [b"treasury"], bump)]
+    pub treasury: Account<'info, Treasury>,
+
+    #[account(mut, seeds = [b"insurance_fund"], bump)]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+}
+
+#[derive(Accounts)]
+pub struct InitInsuranceFund<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = InsuranceFund::SPACE,
+        seeds = [b"insurance_fund"],
+        bump
+    )]
+    pub insurance_fund: Account<'info, InsuranceFund>,
+
+    #[account(mut, address = ADMIN_AUTHORITY)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct InitProtocolConfig<'info> {
+    #[account(
+        init,
+        payer = admin,
+        space = ProtocolConfig::SPACE,
+        seeds = [b"protocol_config"],
+        bump
+    )]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    #[account(mut, address = ADMIN_AUTHORITY)]
+    pub admin: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetProtocolConfig<'info> {
+    #[account(mut, seeds = [b"protocol_config"], bump)]
+    pub protocol_config: Account<'info, ProtocolConfig>,
+
+    #[account(address = ADMIN_AUTHORITY)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitTraderStats<'info> {
+    #[account(
+        init,
+        payer = trader,
+        space = TraderStats::SPACE,
+        seeds = [b"trader_stats", trader.key().as_ref()],
+        bump
+    )]
+    pub trader_stats: Account<'info, TraderStats>,
+
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct SetMarketMaker<'info> {
+    #[account(
+        mut,
+        seeds = [b"trader_stats", trader_stats.trader.as_ref()],
+        bump
+    )]
+    pub trader_stats: Account<'info, TraderStats>,
+
+    #[account(address = ADMIN_AUTHORITY)]
+    pub admin: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct InitPriceHistory<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = payer,
+        space = PriceHistory::SPACE,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump
+    )]
+    pub price_history: AccountLoader<'info, PriceHistory>,
+
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct ObservePrice<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+}
+
+#[derive(Accounts)]
+pub struct CommitTrade<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(
+        init,
+        payer = trader,
+        space = TradeCommit::SPACE,
+        seeds = [b"trade_commit", market.key().as_ref(), trader.key().as_ref()],
+        bump
+    )]
+    pub trade_commit: Account<'info, TradeCommit>,
+
+    #[account(mut)]
+    pub trader: Signer<'info>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+pub struct RevealTrade<'info> {
+    pub trade: Trade<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"trade_commit", trade.market.key().as_ref(), trade.trader.key().as_ref()],
+        bump
+    )]
+    pub trade_commit: Account<'info, TradeCommit>,
+}
+
+#[derive(Accounts)]
+pub struct RefundCommit<'info> {
+    pub market: Account<'info, Market>,
+
+    #[account(
+        mut,
+        seeds = [b"trade_commit", market.key().as_ref(), trader.key().as_ref()],
+        bump
+    )]
+    pub trade_commit: Account<'info, TradeCommit>,
+
+    /// platform treasury (receives the penalty)
+    #[account(mut, seeds = [b"treasury"], bump)]
+    pub treasury: Account<'info, Treasury>,
+
     #[account(mut)]
+    pub trader: Signer<'info>,
+}
+
+#[derive(Accounts)]
+pub struct SubmitBatchOrder<'info> {
     pub market: Account<'info, Market>,
 
+    #[account(
+        init_if_needed,
+        payer = trader,
+        space = OrderQueue::SPACE,
+        seeds = [b"order_queue", market.key().as_ref()],
+        bump
+    )]
+    pub order_queue: Account<'info, OrderQueue>,
+
     #[account(
         init_if_needed,
         payer = trader,
@@ -707,14 +5144,6 @@ pub struct Trade<'info> {
     )]
     pub user_position: Account<'info, UserPosition>,
 
-    /// CHECK: platform wallet (fee receiver)
-    #[account(mut, address = PLATFORM_WALLET)]
-    pub platform_wallet: UncheckedAccount<'info>,
-
-    /// CHECK: creator wallet (fee receiver)
-    #[account(mut, address = market.creator)]
-    pub creator: UncheckedAccount<'info>,
-
     #[account(mut)]
     pub trader: Signer<'info>,
 
@@ -722,42 +5151,94 @@ pub struct Trade<'info> {
 }
 
 #[derive(Accounts)]
-pub struct ProposeResolution<'info> {
-    #[account(mut, has_one = creator)]
+pub struct ClearBatch<'info> {
+    #[account(mut)]
     pub market: Account<'info, Market>,
-    pub creator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
+    #[account(
+        mut,
+        seeds = [b"order_queue", market.key().as_ref()],
+        bump
+    )]
+    pub order_queue: Account<'info, OrderQueue>,
+
+    /// platform treasury (fee accrual)
+    #[account(mut, seeds = [b"treasury"], bump)]
+    pub treasury: Account<'info, Treasury>,
+
+    pub caller: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"price_history", market.key().as_ref()],
+        bump
+    )]
+    pub price_history: Option<AccountLoader<'info, PriceHistory>>,
 }
 
 #[derive(Accounts)]
-pub struct Dispute<'info> {
+pub struct Graduate<'info> {
     #[account(mut)]
     pub market: Account<'info, Market>,
-    pub user: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
+    pub caller: Signer<'info>,
 }
 
 #[derive(Accounts)]
-pub struct AdminResolve<'info> {
+pub struct ClaimWinnings<'info> {
     #[account(mut)]
     pub market: Account<'info, Market>,
 
-    #[account(mut, address = ADMIN_AUTHORITY)]
-    pub admin: Signer<'info>,
-}
+    #[account(
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
 
-#[derive(Accounts)]
-pub struct ClaimCreatorFees<'info> {
-    #[account(mut, has_one = creator)]
-    pub market: Account<'info, Market>,
+    #[account(
+        mut,
+        seeds = [b"user_position", market.key().as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub user_position: Account<'info, UserPosition>,
 
     #[account(mut)]
-    pub creator: Signer<'info>,
+    pub user: Signer<'info>,
+
+    /// platform treasury (settlement fee accrual)
+    #[account(mut, seeds = [b"treasury"], bump)]
+    pub treasury: Account<'info, Treasury>,
+
+    #[account(mut, seeds = [b"insurance_fund"], bump)]
+    pub insurance_fund: Account<'info, InsuranceFund>,
 }
 
 #[derive(Accounts)]
-pub struct ClaimWinnings<'info> {
+pub struct ClaimRefund<'info> {
     #[account(mut)]
     pub market: Account<'info, Market>,
 
+    #[account(
+        mut,
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
     #[account(
         mut,
         seeds = [b"user_position", market.key().as_ref(), user.key().as_ref()],
@@ -767,13 +5248,23 @@ pub struct ClaimWinnings<'info> {
 
     #[account(mut)]
     pub user: Signer<'info>,
+
+    #[account(mut, seeds = [b"insurance_fund"], bump)]
+    pub insurance_fund: Account<'info, InsuranceFund>,
 }
 
 #[derive(Accounts)]
-pub struct ClaimRefund<'info> {
+pub struct ClaimOutcomeRefund<'info> {
     #[account(mut)]
     pub market: Account<'info, Market>,
 
+    #[account(
+        mut,
+        seeds = [b"market_outcomes", market.key().as_ref()],
+        bump
+    )]
+    pub market_outcomes: Account<'info, MarketOutcomes>,
+
     #[account(
         mut,
         seeds = [b"user_position", market.key().as_ref(), user.key().as_ref()],
@@ -781,95 +5272,576 @@ pub struct ClaimRefund<'info> {
     )]
     pub user_position: Account<'info, UserPosition>,
 
-    #[account(mut)]
-    pub user: Signer<'info>,
+    #[account(mut)]
+    pub user: Signer<'info>,
+}
+
+/* ============================== STATE ============================== */
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
+pub enum MarketStatus {
+    Open,
+    Proposed,
+    Finalized,
+    Cancelled,
+}
+
+#[account]
+pub struct Market {
+    pub creator: Pubkey,
+    pub resolution_time: i64,
+
+    pub market_type: u8,
+    pub outcome_count: u8,
+
+    pub b_lamports: u64,
+
+    // linear curve: price = base + slope * supply
+    pub base_price_lamports: u64,
+    pub slope_lamports_per_supply: u64,
+
+    pub status: MarketStatus,
+    pub resolved: bool,
+    pub cancelled: bool,
+    pub winning_outcome: Option<u8>,
+
+    pub proposed_outcome: Option<u8>,
+    pub proposed_at: Option<i64>,
+    pub contest_deadline: Option<i64>,
+    pub dispute_count: u32,
+
+    pub max_position_bps: u16,
+    pub max_trade_shares: u64,
+    pub cooldown_seconds: i64,
+
+    // NEW: escrowed creator fees (lamports)
+    pub creator_fee_escrow: u64,
+
+    // outcome-level voiding: bit i set => outcome i voided
+    pub voided_outcomes: u64,
+    // lamports held back for voided-outcome refunds
+    pub void_reserve_lamports: u64,
+
+    // outstanding minted complete sets: off-curve supply of one share of every outcome each
+    pub complete_sets: u64,
+    pub set_price_lamports: u64,
+
+    // pricing model (PRICING_*) and fpmm liquidity
+    pub pricing_model: u8,
+    pub lp_fee_bps: u16,
+    pub lp_supply: u64,
+    pub lp_fee_per_share: u128, // scaled by LP_FEE_SCALE
+    pub lp_fee_pool_lamports: u64, // accrued, unclaimed LP fees
+
+    // lmsr: subsidy backing b * ln(n), and winning shares already paid out
+    pub subsidy_lamports: u64,
+    pub claimed_winning_shares: u64,
+
+    // graduation (linear -> lmsr)
+    pub volume_lamports: u64,
+    pub graduation_volume_lamports: u64,
+    pub graduation_supply: u64,
+    pub graduation_bonus_bps: u16,
+    pub graduation_bonus_lamports: u64, // withheld platform fee
+    pub graduated: bool,
+
+    // frequent batch auction length (0 = continuous trading)
+    pub batch_seconds: i64,
+
+    // buys go through commit_trade / reveal_trade
+    pub commit_reveal: bool,
+
+    // twap: last time MarketOutcomes.price_cumulative was advanced
+    pub twap_last_ts: i64,
+
+    // creator fee chosen at creation (bps of each trade)
+    pub creator_fee_bps: u16,
+
+    // dynamic fee (0 max = flat fees) and its volatility EMA of spot moves, in bps of set price
+    pub dynamic_fee_max_bps: u16,
+    pub dynamic_fee_ramp_seconds: i64,
+    pub volatility_ema_bps: u64,
+    pub volatility_last_ts: i64,
+
+    // FEE_MODE_*: settlement mode charges platform + creator bps on winnings only
+    pub fee_mode: u8,
+
+    // snapshot at finalization: winners' pool and the supply it is split across
+    pub winning_pool_lamports: u64,
+    pub winning_supply: u64,
+}
+
+impl Market {
+    pub const SPACE: usize =
+        8 +
+        32 +
+        8 +
+        1 +
+        1 +
+        8 +
+        8 + // base_price_lamports
+        8 + // slope_lamports_per_supply
+        1 +
+        1 +
+        1 +
+        (1 + 1) +
+        (1 + 1) +
+        (1 + 8) +
+        (1 + 8) +
+        4 +
+        2 +
+        8 +
+        8 +
+        8 + // NEW creator_fee_escrow
+        8 + // voided_outcomes
+        8 + // void_reserve_lamports
+        8 + // complete_sets
+        8 + // set_price_lamports
+        1 + // pricing_model
+        2 + // lp_fee_bps
+        8 + // lp_supply
+        16 + // lp_fee_per_share
+        8 + // lp_fee_pool_lamports
+        8 + // subsidy_lamports
+        8 + // claimed_winning_shares
+        8 + // volume_lamports
+        8 + // graduation_volume_lamports
+        8 + // graduation_supply
+        2 + // graduation_bonus_bps
+        8 + // graduation_bonus_lamports
+        1 + // graduated
+        8 + // batch_seconds
+        1 + // commit_reveal
+        8 + // twap_last_ts
+        2 + // creator_fee_bps
+        2 + // dynamic_fee_max_bps
+        8 + // dynamic_fee_ramp_seconds
+        8 + // volatility_ema_bps
+        8 + // volatility_last_ts
+        1 + // fee_mode
+        8 + // winning_pool_lamports
+        8; // winning_supply
+
+    pub fn is_voided(&self, idx: usize) -> bool {
+        idx < MAX_OUTCOMES && (self.voided_outcomes >> idx) & 1 == 1
+    }
+
+    pub fn is_parimutuel(&self) -> bool {
+        self.market_type == MARKET_TYPE_PARIMUTUEL
+    }
+
+    /// lmsr-family markets are backed by subsidy and pay a fixed unit per share
+    pub fn uses_subsidy(&self) -> bool {
+        self.pricing_model == PRICING_LMSR || self.pricing_model == PRICING_LS_LMSR
+    }
+}
+
+/// Per-outcome supplies and names, sized to the market's outcome count.
+/// PDA: ["market_outcomes", market]
+#[account]
+pub struct MarketOutcomes {
+    pub market: Pubkey,
+    pub q: Vec<u64>,
+    // aggregate net cost basis per outcome (sum of position entry cost_lamports)
+    pub outcome_cost: Vec<u64>,
+    // pool-held shares: fpmm reserves / lmsr subsidy inventory (zero for linear)
+    pub reserves: Vec<u64>,
+    // sum of spot price * seconds, wrapping (twap = delta cumulative / delta t)
+    pub price_cumulative: Vec<u128>,
+    pub outcome_names: Vec<String>,
+}
+
+impl MarketOutcomes {
+    pub fn space_for(outcome_names: &[String]) -> usize {
+        let n = outcome_names.len();
+        8 +
+        32 +
+        (4 + 8 * n) +
+        (4 + 8 * n) +
+        (4 + 8 * n) +
+        (4 + 16 * n) +
+        4 +
+        outcome_names.iter().map(|s| 4 + s.len()).sum::<usize>()
+    }
+
+    /// Appends an empty outcome; the account must then be grown to `space_for`.
+    pub fn push_outcome(&mut self, name: String) {
+        self.q.push(0);
+        self.outcome_cost.push(0);
+        self.reserves.push(0);
+        self.price_cumulative.push(0);
+        self.outcome_names.push(name);
+    }
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Default)]
+pub struct PositionEntry {
+    pub outcome_index: u8,
+    pub shares: u64,
+    // net cost basis for this outcome (for voided-outcome refunds)
+    pub cost_lamports: u64,
+}
+
+impl PositionEntry {
+    pub const SPACE: usize = 1 + 8 + 8;
+}
+
+#[account]
+pub struct UserPosition {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub claimed: bool,
+    pub last_trade_ts: i64,
+    pub net_cost_lamports: i128,
+    // sparse (outcome_index, shares, cost) entries; only held outcomes are stored
+    pub entries: Vec<PositionEntry>,
+}
+
+impl UserPosition {
+    pub const SPACE: usize =
+        8 +
+        32 +
+        32 +
+        1 +
+        8 +
+        16 +
+        4 +
+        (POSITION_INITIAL_ENTRIES * PositionEntry::SPACE);
+
+    pub fn entry(&self, idx: usize) -> Option<&PositionEntry> {
+        self.entries.iter().find(|e| e.outcome_index as usize == idx)
+    }
+
+    pub fn shares_of(&self, idx: usize) -> u64 {
+        self.entry(idx).map(|e| e.shares).unwrap_or(0)
+    }
+
+    /// Returns the entry for `idx`, inserting an empty one if missing.
+    pub fn entry_mut(&mut self, idx: usize) -> &mut PositionEntry {
+        let pos = match self.entries.iter().position(|e| e.outcome_index as usize == idx) {
+            Some(p) => p,
+            None => {
+                self.entries.push(PositionEntry {
+                    outcome_index: idx as u8,
+                    ..Default::default()
+                });
+                self.entries.len() - 1
+            }
+        };
+        &mut self.entries[pos]
+    }
+
+    pub fn remove_entry(&mut self, idx: usize) {
+        self.entries.retain(|e| e.outcome_index as usize != idx);
+    }
+}
+
+/// Resting order filled against the curve by keepers.
+/// PDA: ["limit_order", market, owner, order_id]
+#[account]
+pub struct LimitOrder {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub order_id: u64,
+    pub outcome_index: u8,
+    pub is_buy: bool,
+    pub shares: u64,
+    pub limit_price_lamports: u64,
+    pub expiry_ts: i64,
+    pub escrow_lamports: u64,      // buys: shares at the limit plus fees
+    pub escrow_cost_lamports: u64, // sells: cost basis of the escrowed shares
+    pub tip_lamports: u64,
+    pub referrer: Pubkey, // default = none
+}
+
+impl LimitOrder {
+    pub const SPACE: usize = 8 + 32 + 32 + 8 + 1 + 1 + 8 + 8 + 8 + 8 + 8 + 8 + 32;
+}
+
+/// One-shot stop-loss / take-profit exit delegated against a position.
+/// PDA: ["trigger_order", user_position, trigger_id]
+#[account]
+pub struct TriggerOrder {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub user_position: Pubkey,
+    pub trigger_id: u64,
+    pub outcome_index: u8,
+    pub max_shares: u64,
+    pub stop_loss_price_lamports: u64,   // 0 = off
+    pub take_profit_price_lamports: u64, // 0 = off
+    pub min_refund_lamports: u64,        // floor on gross proceeds
+    pub tip_lamports: u64,
+}
+
+impl TriggerOrder {
+    pub const SPACE: usize = 8 + 32 + 32 + 32 + 8 + 1 + 8 + 8 + 8 + 8 + 8;
+}
+
+/// Hidden buy intent with its deposit.
+/// PDA: ["trade_commit", market, trader]
+#[account]
+pub struct TradeCommit {
+    pub market: Pubkey,
+    pub trader: Pubkey,
+    pub commitment: [u8; 32],
+    pub deposit_lamports: u64,
+    pub slot: u64,
+}
+
+impl TradeCommit {
+    pub const SPACE: usize = 8 + 32 + 32 + 32 + 8 + 8;
+}
+
+/// Orders collected during the current batch; buy escrow is held here.
+/// PDA: ["order_queue", market]
+#[account]
+pub struct OrderQueue {
+    pub market: Pubkey,
+    pub batch_id: u64,
+    pub batch_end_ts: i64,
+    pub orders: Vec<QueuedOrder>,
+}
+
+impl OrderQueue {
+    pub const SPACE: usize =
+        8 +
+        32 +
+        8 +
+        8 +
+        4 + MAX_QUEUED_ORDERS * QueuedOrder::SPACE;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct QueuedOrder {
+    pub trader: Pubkey,
+    pub outcome_index: u8,
+    pub is_buy: bool,
+    pub shares: u64,
+    pub limit_price_lamports: u64,
+    pub escrow_lamports: u64,      // buys: lamports held by the queue
+    pub escrow_cost_lamports: u64, // sells: cost basis of the escrowed shares
+}
+
+impl QueuedOrder {
+    pub const SPACE: usize = 32 + 1 + 1 + 8 + 8 + 8 + 8;
+}
+
+/// Program-owned sink for platform fees, paid out by `distribute_treasury`.
+/// PDA: ["treasury"]
+#[account]
+pub struct Treasury {
+    pub authority: Pubkey, // may change the split
+    pub recipients: Vec<TreasuryRecipient>, // bps sum to 10_000
+    pub total_distributed_lamports: u64,
+    pub insurance_bps: u16, // skimmed to the insurance fund before the split
+}
+
+impl Treasury {
+    pub const SPACE: usize = 8 + 32 + (4 + MAX_TREASURY_RECIPIENTS * TreasuryRecipient::SPACE) + 8 + 2;
+}
+
+/// Backstop for claims a market can't cover; its lamports above rent are the fund.
+/// PDA: ["insurance_fund"]
+#[account]
+pub struct InsuranceFund {
+    pub total_contributed_lamports: u64,
+    pub total_drawn_lamports: u64,
+}
+
+impl InsuranceFund {
+    pub const SPACE: usize = 8 + 8 + 8;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct TreasuryRecipient {
+    pub wallet: Pubkey,
+    pub bps: u16,
+}
+
+impl TreasuryRecipient {
+    pub const SPACE: usize = 32 + 2;
+}
+
+/// Protocol-wide fee settings.
+/// PDA: ["protocol_config"]
+#[account]
+pub struct ProtocolConfig {
+    pub tiers: Vec<FeeTier>, // ascending min_volume_lamports
+    pub maker_rebate_bps: u16,
+}
+
+impl ProtocolConfig {
+    pub const SPACE: usize = 8 + (4 + MAX_FEE_TIERS * FeeTier::SPACE) + 2;
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct FeeTier {
+    pub min_volume_lamports: u64, // 30-day volume to reach this tier
+    pub platform_fee_bps: u16,
+}
+
+impl FeeTier {
+    pub const SPACE: usize = 8 + 2;
+}
+
+/// Rolling 30-day trade volume in daily buckets.
+/// PDA: ["trader_stats", trader]
+#[account]
+pub struct TraderStats {
+    pub trader: Pubkey,
+    pub last_day: i64, // unix day of the newest bucket
+    pub day_volumes: [u64; VOLUME_WINDOW_DAYS], // indexed by day % VOLUME_WINDOW_DAYS
+    pub market_maker: bool,
+    pub lifetime_volume_lamports: u64,
+    pub rebates_lamports: u64,
 }
 
-/* ============================== STATE ============================== */
+impl TraderStats {
+    pub const SPACE: usize = 8 + 32 + 8 + 8 * VOLUME_WINDOW_DAYS + 1 + 8 + 8;
 
-#[derive(AnchorSerialize, AnchorDeserialize, Clone, PartialEq, Eq)]
-pub enum MarketStatus {
-    Open,
-    Proposed,
-    Finalized,
-    Cancelled,
+    /// Advances to `now`'s day, clearing buckets that fell out of the window.
+    pub fn roll(&mut self, now: i64) {
+        let today = now / 86_400;
+        if today <= self.last_day {
+            return;
+        }
+        let stale = ((today - self.last_day) as usize).min(VOLUME_WINDOW_DAYS);
+        for d in 1..=stale {
+            let slot = (self.last_day as usize + d) % VOLUME_WINDOW_DAYS;
+            self.day_volumes[slot] = 0;
+        }
+        self.last_day = today;
+    }
+
+    pub fn record(&mut self, now: i64, amount: u64, rebate: u64) {
+        self.roll(now);
+        let slot = self.last_day as usize % VOLUME_WINDOW_DAYS;
+        self.day_volumes[slot] = self.day_volumes[slot].saturating_add(amount);
+        self.lifetime_volume_lamports = self.lifetime_volume_lamports.saturating_add(amount);
+        self.rebates_lamports = self.rebates_lamports.saturating_add(rebate);
+    }
+
+    pub fn volume_30d(&self) -> u64 {
+        self.day_volumes.iter().fold(0u64, |acc, v| acc.saturating_add(*v))
+    }
 }
 
+/// Registered referrer; accrued fees sit in this account until claimed.
+/// PDA: ["referrer", wallet]
 #[account]
-pub struct Market {
-    pub creator: Pubkey,
-    pub resolution_time: i64,
+pub struct Referrer {
+    pub wallet: Pubkey,
+    pub share_bps: u16, // of the platform fee, 0..MAX_REFERRER_SHARE_BPS
+    pub accrued_lamports: u64,
+    pub total_earned_lamports: u64,
+}
 
-    pub market_type: u8,
-    pub outcome_count: u8,
+impl Referrer {
+    pub const SPACE: usize = 8 + 32 + 2 + 8 + 8;
+}
 
-    pub b_lamports: u64,
-    pub q: [u64; MAX_OUTCOMES],
+/// Ring buffer of fixed-interval candles, shared by all outcomes of a market
+/// (at most PRICE_HISTORY_MAX_OUTCOMES of them).
+/// PDA: ["price_history", market]
+#[account(zero_copy)]
+pub struct PriceHistory {
+    pub market: Pubkey,
+    pub interval_seconds: i64,
+    pub head: u16, // next slot to write
+    pub len: u16,  // filled slots, up to PRICE_HISTORY_CANDLES
+    pub _padding: [u8; 4],
+    pub last_slot: [u16; MAX_OUTCOMES], // each outcome's latest candle (may be overwritten)
+    pub candles: [Candle; PRICE_HISTORY_CANDLES],
+}
 
-    pub status: MarketStatus,
-    pub resolved: bool,
-    pub cancelled: bool,
-    pub winning_outcome: Option<u8>,
+impl PriceHistory {
+    pub const SPACE: usize = 8 + 32 + 8 + 2 + 2 + 4 + 2 * MAX_OUTCOMES + PRICE_HISTORY_CANDLES * Candle::SPACE;
+
+    /// Updates the outcome's candle for `bucket`, or opens a new one at the head.
+    pub fn record(&mut self, idx: usize, bucket: i64, price: u64, volume_lamports: u64) {
+        let slot = self.last_slot[idx] as usize;
+        let filled = self.len > 0;
+        let c = &mut self.candles[slot];
+        if filled && c.outcome_index as usize == idx && c.start_ts == bucket {
+            c.high = c.high.max(price);
+            c.low = c.low.min(price);
+            c.close = price;
+            c.volume_lamports = c.volume_lamports.saturating_add(volume_lamports);
+            return;
+        }
 
-    pub proposed_outcome: Option<u8>,
-    pub proposed_at: Option<i64>,
-    pub contest_deadline: Option<i64>,
-    pub dispute_count: u32,
+        let head = self.head as usize;
+        self.candles[head] = Candle {
+            start_ts: bucket,
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume_lamports,
+            outcome_index: idx as u8,
+            _padding: [0; 7],
+        };
+        self.last_slot[idx] = head as u16;
+        self.head = ((head + 1) % PRICE_HISTORY_CANDLES) as u16;
+        self.len = (self.len as usize + 1).min(PRICE_HISTORY_CANDLES) as u16;
+    }
+}
 
-    pub max_position_bps: u16,
-    pub max_trade_shares: u64,
-    pub cooldown_seconds: i64,
+/// Spot prices in lamports per share; volume in lamports traded.
+#[zero_copy]
+pub struct Candle {
+    pub start_ts: i64,
+    pub open: u64,
+    pub high: u64,
+    pub low: u64,
+    pub close: u64,
+    pub volume_lamports: u64,
+    pub outcome_index: u8,
+    pub _padding: [u8; 7],
+}
 
-    // NEW: escrowed creator fees (lamports)
-    pub creator_fee_escrow: u64,
+impl Candle {
+    pub const SPACE: usize = 8 + 8 + 8 + 8 + 8 + 8 + 1 + 7;
+}
 
-    pub outcome_names: Vec<String>,
+/// Checkpoint for get_twap: cumulative price of one outcome at `timestamp`.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct PriceObservation {
+    pub price_cumulative: u128,
+    pub timestamp: i64,
 }
 
-impl Market {
-    pub const SPACE: usize =
-        8 +
-        32 +
-        8 +
-        1 +
-        1 +
-        8 +
-        (8 * MAX_OUTCOMES) +
-        1 +
-        1 +
-        1 +
-        (1 + 1) +
-        (1 + 1) +
-        (1 + 8) +
-        (1 + 8) +
-        4 +
-        2 +
-        8 +
-        8 +
-        8 + // NEW creator_fee_escrow
-        4 +
-        (MAX_OUTCOMES * (4 + MAX_NAME_LEN));
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy)]
+pub struct TradeLeg {
+    pub outcome_index: u8,
+    pub shares: u64,
+    pub is_buy: bool,
 }
 
+/// LP shares in an fpmm pool or an lmsr subsidy.
+/// PDA: ["lp_position", market, owner]
 #[account]
-pub struct UserPosition {
+pub struct LiquidityPosition {
     pub market: Pubkey,
-    pub user: Pubkey,
-    pub shares: [u64; MAX_OUTCOMES],
-    pub claimed: bool,
-    pub last_trade_ts: i64,
-    pub net_cost_lamports: i128,
+    pub owner: Pubkey,
+    pub lp_shares: u64,
+    pub fee_debt: u128, // lp_shares * lp_fee_per_share at last settle
+    pub fees_owed: u64,
+    pub net_deposit_lamports: u64, // refunded on cancel
+    pub reserves_redeemed: bool, // pool share paid out after finalization
 }
 
-impl UserPosition {
+impl LiquidityPosition {
     pub const SPACE: usize =
         8 +
         32 +
         32 +
-        (8 * MAX_OUTCOMES) +
-        1 +
         8 +
-        16;
+        16 +
+        8 +
+        8 +
+        1;
 }
 
 /* ============================== EVENTS ============================== */
@@ -882,6 +5854,189 @@ pub struct MarketCreated {
     pub market_type: u8,
     pub outcome_count: u8,
     pub b_lamports: u64,
+    pub pricing_model: u8,
+    pub base_price_lamports: u64,
+    pub slope_lamports_per_supply: u64,
+    pub creator_fee_bps: u16,
+}
+
+#[event]
+pub struct LimitOrderPlaced {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub order: Pubkey,
+    pub outcome_index: u8,
+    pub is_buy: bool,
+    pub shares: u64,
+    pub limit_price_lamports: u64,
+    pub expiry_ts: i64,
+}
+
+#[event]
+pub struct LimitOrderFilled {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub order: Pubkey,
+    pub keeper: Pubkey,
+    pub amount_lamports: u64,
+    pub tip_lamports: u64,
+}
+
+#[event]
+pub struct LimitOrderCancelled {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub order: Pubkey,
+}
+
+#[event]
+pub struct InsuranceDraw {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub kind: ClaimKind,
+    pub shortfall_lamports: u64,
+}
+
+#[event]
+pub struct TreasurySplitUpdated {
+    pub authority: Pubkey,
+    pub recipient_count: u8,
+}
+
+#[event]
+pub struct TreasuryDistributed {
+    pub amount_lamports: u64,
+    pub insurance_lamports: u64,
+    pub recipient_count: u8,
+}
+
+#[event]
+pub struct FeeTiersUpdated {
+    pub tier_count: u8,
+    pub maker_rebate_bps: u16,
+}
+
+#[event]
+pub struct MarketMakerUpdated {
+    pub trader: Pubkey,
+    pub market_maker: bool,
+}
+
+#[event]
+pub struct ReferrerUpdated {
+    pub referrer: Pubkey,
+    pub wallet: Pubkey,
+    pub share_bps: u16,
+}
+
+#[event]
+pub struct ReferrerFeesClaimed {
+    pub referrer: Pubkey,
+    pub wallet: Pubkey,
+    pub amount_lamports: u64,
+}
+
+#[event]
+pub struct PriceHistoryInitialized {
+    pub market: Pubkey,
+    pub interval_seconds: i64,
+    pub by: Pubkey,
+}
+
+#[event]
+pub struct TriggerOrderPlaced {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub trigger: Pubkey,
+    pub outcome_index: u8,
+    pub max_shares: u64,
+    pub stop_loss_price_lamports: u64,
+    pub take_profit_price_lamports: u64,
+    pub min_refund_lamports: u64,
+}
+
+#[event]
+pub struct TriggerExecuted {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub trigger: Pubkey,
+    pub keeper: Pubkey,
+    pub spot_price_lamports: u64,
+    pub shares: u64,
+    pub amount_lamports: u64,
+    pub tip_lamports: u64,
+}
+
+#[event]
+pub struct TriggerOrderCancelled {
+    pub market: Pubkey,
+    pub owner: Pubkey,
+    pub trigger: Pubkey,
+}
+
+#[event]
+pub struct TradeCommitted {
+    pub market: Pubkey,
+    pub trader: Pubkey,
+    pub commitment: [u8; 32],
+    pub deposit_lamports: u64,
+    pub slot: u64,
+}
+
+#[event]
+pub struct TradeRevealed {
+    pub market: Pubkey,
+    pub trader: Pubkey,
+    pub outcome_index: u8,
+    pub shares: u64,
+    pub deposit_lamports: u64,
+}
+
+#[event]
+pub struct CommitRefunded {
+    pub market: Pubkey,
+    pub trader: Pubkey,
+    pub refund_lamports: u64,
+    pub penalty_lamports: u64,
+}
+
+#[event]
+pub struct BatchOrderQueued {
+    pub market: Pubkey,
+    pub trader: Pubkey,
+    pub batch_id: u64,
+    pub outcome_index: u8,
+    pub is_buy: bool,
+    pub shares: u64,
+    pub limit_price_lamports: u64,
+}
+
+#[event]
+pub struct BatchCleared {
+    pub market: Pubkey,
+    pub batch_id: u64,
+    pub outcome_index: u8,
+    pub clearing_price_lamports: u64,
+    pub buy_shares: u64,
+    pub sell_shares: u64,
+}
+
+#[event]
+pub struct MarketGraduated {
+    pub market: Pubkey,
+    pub by: Pubkey,
+    pub volume_lamports: u64,
+    pub total_supply: u64,
+    pub set_price_lamports: u64,
+    pub b_lamports: u64,
+    pub subsidy_lamports: u64,
+    pub creator_bonus_lamports: u64,
+}
+
+#[event]
+pub struct GraduationResidualSwept {
+    pub market: Pubkey,
+    pub amount_lamports: u64,
 }
 
 #[event]
@@ -894,6 +6049,34 @@ pub struct TradeExecuted {
     pub amount_lamports: u64,
     pub platform_fee_lamports: u64,
     pub creator_fee_lamports: u64,
+    pub lp_fee_lamports: u64,
+    pub referrer_fee_lamports: u64,
+    pub platform_fee_bps: u16, // applied rate after tiers and dynamic adjustment
+}
+
+#[event]
+pub struct CompleteSetTraded {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub is_mint: bool,
+    pub sets: u64,
+    pub amount_lamports: u64,
+}
+
+#[event]
+pub struct LiquidityChanged {
+    pub market: Pubkey,
+    pub provider: Pubkey,
+    pub is_add: bool,
+    pub lp_shares: u64,
+    pub amount_lamports: u64,
+}
+
+#[event]
+pub struct LpFeesClaimed {
+    pub market: Pubkey,
+    pub provider: Pubkey,
+    pub amount_lamports: u64,
 }
 
 #[event]
@@ -938,10 +6121,37 @@ pub struct Cancelled {
     pub reason: CancelReason,
 }
 
+#[event]
+pub struct OutcomeAdded {
+    pub market: Pubkey,
+    pub outcome_index: u8,
+    pub outcome_name: String,
+    pub outcome_count: u8,
+    pub by: Pubkey,
+}
+
+#[event]
+pub struct OutcomeVoided {
+    pub market: Pubkey,
+    pub outcome_index: u8,
+    pub by: Pubkey,
+    pub reserved_lamports: u64,
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, PartialEq, Eq)]
 pub enum ClaimKind {
     Winnings,
     Refund,
+    OutcomeRefund,
+}
+
+#[event]
+pub struct SettlementFeeCharged {
+    pub market: Pubkey,
+    pub user: Pubkey,
+    pub payout_lamports: u64, // gross, before fees
+    pub platform_fee_lamports: u64,
+    pub creator_fee_lamports: u64,
 }
 
 #[event]
@@ -1027,6 +6237,245 @@ pub enum ErrorCode {
     #[msg("Invalid user position account")]
     InvalidUserPosition,
 
+    #[msg("Outcome is voided")]
+    OutcomeVoided,
+    #[msg("Outcome is not voided")]
+    OutcomeNotVoided,
+    #[msg("Too many outcomes")]
+    TooManyOutcomes,
+    #[msg("Slippage exceeded")]
+    SlippageExceeded,
+    #[msg("Invalid batch")]
+    InvalidBatch,
+    #[msg("Invalid curve parameters")]
+    InvalidCurveParams,
+    #[msg("Order expired")]
+    OrderExpired,
+    #[msg("Curve price does not meet the order limit")]
+    LimitNotReached,
+    #[msg("Invalid trigger prices")]
+    InvalidTrigger,
+    #[msg("Spot price has not crossed the trigger")]
+    TriggerNotReached,
+    #[msg("Invalid treasury split")]
+    InvalidTreasurySplit,
+    #[msg("Trader cannot refer themselves")]
+    SelfReferral,
+    #[msg("Referrer does not match the order")]
+    ReferrerMismatch,
+    #[msg("Invalid fee config")]
+    InvalidFeeConfig,
+    #[msg("Invalid candle interval")]
+    InvalidCandleInterval,
+    #[msg("Invalid price observation")]
+    InvalidObservation,
+    #[msg("Market requires commit-reveal for buys")]
+    CommitRevealRequired,
+    #[msg("Reveal must come at least one slot after the commit")]
+    RevealTooEarly,
+    #[msg("Reveal window expired")]
+    RevealExpired,
+    #[msg("Reveal does not match commitment")]
+    CommitmentMismatch,
+    #[msg("Deposit does not cover max pay")]
+    InsufficientDeposit,
+    #[msg("Commit can still be revealed")]
+    CommitStillActive,
+    #[msg("Invalid batch auction config")]
+    InvalidBatchConfig,
+    #[msg("Market trades through batch auction orders")]
+    BatchAuctionOnly,
+    #[msg("Previous batch must be cleared first")]
+    BatchPendingClear,
+    #[msg("Batch is still collecting orders")]
+    BatchStillOpen,
+    #[msg("Batch order queue is full")]
+    BatchFull,
+    #[msg("Too many queued orders for this trader")]
+    TooManyQueuedOrders,
+    #[msg("No orders in batch")]
+    EmptyBatch,
+    #[msg("Selling is disabled for this market")]
+    SellDisabled,
+    #[msg("Invalid graduation config")]
+    InvalidGraduationConfig,
+    #[msg("Graduation threshold not met")]
+    GraduationThresholdNotMet,
+    #[msg("Market already graduated")]
+    AlreadyGraduated,
+    #[msg("Invalid pricing model")]
+    InvalidPricingModel,
+    #[msg("Not enough pool liquidity")]
+    NoLiquidity,
+    #[msg("Complete sets are outstanding")]
+    CompleteSetsOutstanding,
+
     #[msg("Overflow")]
     Overflow,
-}
\ No newline at end of file
+
+    // math_lmsr
+    #[msg("Math overflow")]
+    MathOverflow,
+    #[msg("Invalid liquidity parameter")]
+    InvalidLiquidityParameter,
+    #[msg("Invalid outcome count")]
+    InvalidOutcomeCount,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank_market() -> Market {
+        Market::try_deserialize_unchecked(&mut &vec![0u8; Market::SPACE][..]).unwrap()
+    }
+
+    fn blank_outcomes(n: usize) -> MarketOutcomes {
+        MarketOutcomes {
+            market: Pubkey::default(),
+            q: vec![0; n],
+            outcome_cost: vec![0; n],
+            reserves: vec![0; n],
+            price_cumulative: vec![0; n],
+            outcome_names: (0..n).map(|i| format!("o{i}")).collect(),
+        }
+    }
+
+    fn blank_position() -> UserPosition {
+        UserPosition {
+            market: Pubkey::default(),
+            user: Pubkey::default(),
+            claimed: false,
+            last_trade_ts: 0,
+            net_cost_lamports: 0,
+            entries: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn equal_winners_split_the_pool_evenly() {
+        // two winners with 100 shares each against a 1000-lamport pool
+        let (pool, supply) = (1_000u64, 200u64);
+        let mut balance = pool;
+
+        let first = pro_rata_payout(100, pool, supply).unwrap();
+        balance -= first;
+        let second = pro_rata_payout(100, pool, supply).unwrap();
+        balance -= second;
+
+        assert_eq!(first, 500);
+        assert_eq!(second, 500);
+        assert_eq!(balance, 0);
+    }
+
+    #[test]
+    fn payouts_never_exceed_the_pool() {
+        let (pool, supply) = (1_000_003u64, 7u64);
+        let paid: u64 = [1u64, 2, 4].iter().map(|s| pro_rata_payout(*s, pool, supply).unwrap()).sum();
+        assert!(paid <= pool);
+        assert!(pro_rata_payout(1, pool, 0).is_err());
+    }
+
+    #[test]
+    fn parimutuel_markets_have_no_complete_sets() {
+        let mut market = blank_market();
+        assert!(require_complete_sets_enabled(&market).is_ok());
+
+        market.market_type = MARKET_TYPE_PARIMUTUEL;
+        assert!(require_complete_sets_enabled(&market).is_err());
+    }
+
+    #[test]
+    fn voided_refund_survives_claimed_winnings() {
+        let mut market = blank_market();
+        market.outcome_count = 2;
+        let mut outcomes = blank_outcomes(2);
+        let mut pos = blank_position();
+
+        // 40 shares of outcome 1 bought for 400, then outcome 1 voided
+        pos.entries.push(PositionEntry { outcome_index: 1, shares: 40, cost_lamports: 400 });
+        pos.net_cost_lamports = 400;
+        outcomes.q[1] = 40;
+        outcomes.outcome_cost[1] = 400;
+        market.voided_outcomes |= 1 << 1;
+        market.void_reserve_lamports = 400;
+
+        // claim_winnings on the other outcome marks the position claimed
+        pos.claimed = true;
+
+        assert_eq!(release_voided_entry(&mut market, &mut outcomes, &mut pos, 1).unwrap(), 400);
+        assert_eq!(market.void_reserve_lamports, 0);
+        assert_eq!(outcomes.q[1], 0);
+        assert_eq!(pos.net_cost_lamports, 0);
+        assert!(pos.entry(1).is_none());
+
+        // the entry is gone, so a second claim has nothing to pay
+        assert!(release_voided_entry(&mut market, &mut outcomes, &mut pos, 1).is_err());
+    }
+
+    #[test]
+    fn position_entry_helpers() {
+        let mut pos = blank_position();
+        assert_eq!(pos.shares_of(3), 0);
+        assert!(pos.entry(3).is_none());
+
+        // entry_mut inserts once, then hands back the same entry
+        pos.entry_mut(3).shares = 10;
+        pos.entry_mut(3).shares += 5;
+        pos.entry_mut(1).shares = 7;
+        assert_eq!(pos.entries.len(), 2);
+        assert_eq!(pos.shares_of(3), 15);
+        assert_eq!(pos.shares_of(1), 7);
+
+        pos.remove_entry(3);
+        assert_eq!(pos.shares_of(3), 0);
+        assert_eq!(pos.shares_of(1), 7);
+        assert_eq!(pos.entries.len(), 1);
+
+        // removing a missing entry is a no-op
+        pos.remove_entry(3);
+        assert_eq!(pos.entries.len(), 1);
+    }
+
+    #[test]
+    fn added_outcome_matches_space_for() {
+        let mut outcomes = blank_outcomes(2);
+        let before = 8 + outcomes.try_to_vec().unwrap().len();
+        assert_eq!(before, MarketOutcomes::space_for(&outcomes.outcome_names));
+
+        outcomes.push_outcome("late entrant".to_string());
+        let after = 8 + outcomes.try_to_vec().unwrap().len();
+        assert_eq!(after, MarketOutcomes::space_for(&outcomes.outcome_names));
+        assert_eq!(after - before, 8 + 8 + 8 + 16 + 4 + "late entrant".len());
+        assert_eq!(outcomes.q.len(), 3);
+        assert_eq!(outcomes.price_cumulative.len(), 3);
+    }
+
+    #[test]
+    fn reserved_lamports_sums_every_reserve() {
+        let mut market = blank_market();
+        assert_eq!(reserved_lamports(&market).unwrap(), 0);
+
+        market.void_reserve_lamports = 1;
+        market.lp_fee_pool_lamports = 20;
+        market.creator_fee_escrow = 300;
+        market.graduation_bonus_lamports = 4_000;
+        assert_eq!(reserved_lamports(&market).unwrap(), 4_321);
+
+        market.graduation_bonus_lamports = u64::MAX;
+        assert!(reserved_lamports(&market).is_err());
+    }
+
+    #[test]
+    fn shortfall_keeps_reserves_and_rent() {
+        // 1_000 reserved + 500 owed + 100 rent against 1_600: fully covered
+        assert_eq!(shortfall_lamports(1_000, 500, 100, 1_600).unwrap(), 0);
+        assert_eq!(shortfall_lamports(1_000, 500, 100, 2_000).unwrap(), 0);
+
+        // the fund only tops up what the market lacks
+        assert_eq!(shortfall_lamports(1_000, 500, 100, 1_450).unwrap(), 150);
+        assert_eq!(shortfall_lamports(1_000, 500, 100, 0).unwrap(), 1_600);
+
+        assert!(shortfall_lamports(u64::MAX, 1, 0, 0).is_err());
+    }
+}
@@ -0,0 +1,213 @@
+// programs/funmarket-pump/src/math_fpmm.rs
+//
+// Fixed-product market maker math (Gnosis FPMM style).
+//
+// - reserves are pool-held outcome shares, collateral is counted in complete sets
+// - buy: pool mints `x` sets, hands out shares of outcome i, prod(reserves) is kept
+// - sell: pool takes shares of outcome i, merges `x` sets back into collateral
+// - products are computed as running ratios so 64 outcomes never overflow u128
+
+use anchor_lang::prelude::*;
+use crate::ErrorCode as MainErrorCode;
+
+#[inline]
+fn div_ceil_u128(n: u128, d: u128) -> Result<u128> {
+    require!(d > 0, MainErrorCode::Overflow);
+    Ok(n.div_ceil(d))
+}
+
+/// Shares of outcome i handed out for `sets` of collateral.
+/// out = r_i + x - r_i * prod_{j!=i} r_j / (r_j + x)
+pub fn fpmm_buy_out(reserves: &[u64], outcome_index: usize, sets: u64) -> Result<u64> {
+    require!(outcome_index < reserves.len(), MainErrorCode::InvalidOutcomeIndex);
+
+    let x = sets as u128;
+    let r_i = reserves[outcome_index] as u128;
+    require!(r_i > 0, MainErrorCode::NoLiquidity);
+
+    let mut ending = r_i;
+    for (j, r_j) in reserves.iter().enumerate() {
+        if j == outcome_index {
+            continue;
+        }
+        let r_j = *r_j as u128;
+        let den = r_j.checked_add(x).ok_or(MainErrorCode::Overflow)?;
+        ending = div_ceil_u128(ending.checked_mul(r_j).ok_or(MainErrorCode::Overflow)?, den)?;
+    }
+
+    let out = r_i
+        .checked_add(x)
+        .ok_or(MainErrorCode::Overflow)?
+        .checked_sub(ending)
+        .ok_or(MainErrorCode::Overflow)?;
+
+    u64::try_from(out).map_err(|_| error!(MainErrorCode::Overflow))
+}
+
+/// Shares of outcome i the pool must receive to release `sets` of collateral.
+/// sell = x + r_i * prod_{j!=i} r_j / (r_j - x) - r_i
+pub fn fpmm_sell_in(reserves: &[u64], outcome_index: usize, sets: u64) -> Result<u64> {
+    require!(outcome_index < reserves.len(), MainErrorCode::InvalidOutcomeIndex);
+
+    let x = sets as u128;
+    let r_i = reserves[outcome_index] as u128;
+    require!(r_i > 0, MainErrorCode::NoLiquidity);
+
+    let mut ending = r_i;
+    for (j, r_j) in reserves.iter().enumerate() {
+        if j == outcome_index {
+            continue;
+        }
+        let r_j = *r_j as u128;
+        require!(r_j > x, MainErrorCode::NoLiquidity);
+        ending = div_ceil_u128(ending.checked_mul(r_j).ok_or(MainErrorCode::Overflow)?, r_j - x)?;
+    }
+
+    let sell = x
+        .checked_add(ending)
+        .ok_or(MainErrorCode::Overflow)?
+        .checked_sub(r_i)
+        .ok_or(MainErrorCode::Overflow)?;
+
+    u64::try_from(sell).map_err(|_| error!(MainErrorCode::Overflow))
+}
+
+/// Minimum sets of collateral needed to receive at least `shares` of outcome i.
+/// out(x) >= x always holds, so the answer lies in [1, shares].
+pub fn fpmm_sets_to_buy(reserves: &[u64], outcome_index: usize, shares: u64) -> Result<u64> {
+    require!(shares > 0, MainErrorCode::InvalidShares);
+
+    let (mut lo, mut hi) = (1u64, shares);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if fpmm_buy_out(reserves, outcome_index, mid)? >= shares {
+            hi = mid;
+        } else {
+            lo = mid + 1;
+        }
+    }
+
+    Ok(lo)
+}
+
+/// Maximum sets of collateral released by selling `shares` of outcome i (0 if none).
+/// sell(x) >= x and every other reserve must stay positive, which bounds the search.
+pub fn fpmm_sets_for_sell(reserves: &[u64], outcome_index: usize, shares: u64) -> Result<u64> {
+    require!(shares > 0, MainErrorCode::InvalidShares);
+    require!(outcome_index < reserves.len(), MainErrorCode::InvalidOutcomeIndex);
+
+    let min_other = reserves
+        .iter()
+        .enumerate()
+        .filter(|(j, _)| *j != outcome_index)
+        .map(|(_, r)| *r)
+        .min()
+        .unwrap_or(0);
+    if min_other <= 1 {
+        return Ok(0);
+    }
+
+    let (mut lo, mut hi) = (0u64, shares.min(min_other - 1));
+    while lo < hi {
+        let mid = lo + (hi - lo).div_ceil(2);
+        if fpmm_sell_in(reserves, outcome_index, mid)? <= shares {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+
+    Ok(lo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: u64 = 1_000_000;
+
+    fn product(reserves: &[u64]) -> f64 {
+        reserves.iter().map(|r| *r as f64).product()
+    }
+
+    /// Applies a buy of `sets` on outcome i: mint sets into the pool, hand out `out`.
+    fn after_buy(reserves: &[u64], i: usize, sets: u64, out: u64) -> Vec<u64> {
+        reserves
+            .iter()
+            .enumerate()
+            .map(|(j, r)| if j == i { r + sets - out } else { r + sets })
+            .collect()
+    }
+
+    #[test]
+    fn buy_keeps_product_at_least_constant() {
+        for reserves in [vec![SEED, SEED], vec![SEED, 3 * SEED, SEED / 2], vec![SEED; 8]] {
+            for sets in [1u64, 1_000, SEED / 3, 2 * SEED] {
+                let out = fpmm_buy_out(&reserves, 0, sets).unwrap();
+                assert!(out >= sets, "out={out} sets={sets}");
+
+                let after = after_buy(&reserves, 0, sets, out);
+                // ceil rounding leaves the pool no worse off
+                assert!(product(&after) >= product(&reserves) * (1.0 - 1e-12));
+            }
+        }
+    }
+
+    #[test]
+    fn buy_then_sell_round_trip_is_not_profitable() {
+        let reserves = vec![SEED, 2 * SEED, SEED];
+        for sets in [10u64, 10_000, SEED / 2] {
+            let out = fpmm_buy_out(&reserves, 1, sets).unwrap();
+            let after = after_buy(&reserves, 1, sets, out);
+
+            // the shares just bought can't release more collateral than they cost
+            let back = fpmm_sets_for_sell(&after, 1, out).unwrap();
+            assert!(back <= sets, "sets={sets} back={back}");
+
+            let needed = fpmm_sell_in(&after, 1, sets).unwrap();
+            assert!(needed >= out, "out={out} needed={needed}");
+        }
+    }
+
+    #[test]
+    fn sets_to_buy_is_the_minimum() {
+        let reserves = vec![SEED, 5 * SEED, 2 * SEED];
+        for shares in [1u64, 777, SEED, 4 * SEED] {
+            let sets = fpmm_sets_to_buy(&reserves, 0, shares).unwrap();
+            assert!((1..=shares).contains(&sets));
+            assert!(fpmm_buy_out(&reserves, 0, sets).unwrap() >= shares);
+            if sets > 1 {
+                assert!(fpmm_buy_out(&reserves, 0, sets - 1).unwrap() < shares);
+            }
+        }
+        assert!(fpmm_sets_to_buy(&reserves, 0, 0).is_err());
+    }
+
+    #[test]
+    fn sets_for_sell_is_the_maximum_and_bounded() {
+        let reserves = vec![SEED, SEED / 4, 3 * SEED];
+        for shares in [1u64, 500, SEED, 10 * SEED] {
+            let sets = fpmm_sets_for_sell(&reserves, 0, shares).unwrap();
+            // other reserves must stay positive
+            assert!(sets < SEED / 4);
+            assert!(sets <= shares);
+            if sets > 0 {
+                assert!(fpmm_sell_in(&reserves, 0, sets).unwrap() <= shares);
+            }
+            if sets + 1 < SEED / 4 {
+                assert!(fpmm_sell_in(&reserves, 0, sets + 1).unwrap() > shares);
+            }
+        }
+
+        // a drained counter-reserve releases nothing
+        assert_eq!(fpmm_sets_for_sell(&[SEED, 1], 0, SEED).unwrap(), 0);
+        assert!(fpmm_sets_for_sell(&reserves, 3, 1).is_err());
+    }
+
+    #[test]
+    fn empty_reserve_has_no_liquidity() {
+        assert!(fpmm_buy_out(&[0, SEED], 0, 10).is_err());
+        assert!(fpmm_sell_in(&[0, SEED], 0, 10).is_err());
+        assert!(fpmm_sell_in(&[SEED, 10], 0, 10).is_err());
+    }
+}
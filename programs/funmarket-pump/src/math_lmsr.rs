@@ -1,15 +1,16 @@
-// programs/funmarket-pump/src/math.rs
+// programs/funmarket-pump/src/math_lmsr.rs
 //
-// Legacy LMSR fixed-point math (1e9 scale).
-// NOTE: If you switched the contract pricing to a linear curve in lib.rs,
-// this module is kept for compatibility / future use and is not called anymore.
+// LMSR fixed-point math (1e9 scale).
+// Used by markets created with PRICING_LMSR / PRICING_LS_LMSR; linear markets never call it.
 //
 // - log-sum-exp to avoid overflow
+// - LS-LMSR (Othman et al.): b = alpha * sum(q), so depth grows with volume
 // - supports negative exponent inputs via reciprocal
 // - rounds COST up (ceil) so buy cost doesn't truncate to 0 lamport
 
 use anchor_lang::prelude::*;
 use crate::ErrorCode as MainErrorCode;
+use crate::MAX_OUTCOMES;
 
 /// Scale factor for fixed-point arithmetic (1e9)
 pub const SCALE: u128 = 1_000_000_000;
@@ -153,14 +154,15 @@ pub fn exp_fixed_signed(x: i128) -> Result<u128> {
 ///
 /// Uses log-sum-exp for stability:
 /// ln(sum exp(r_i)) = m + ln(sum exp(r_i - m))
-pub fn lmsr_cost(q: &[u64; 10], b: u64, outcome_count: u8) -> Result<u64> {
+pub fn lmsr_cost(q: &[u64], b: u64, outcome_count: u8) -> Result<u64> {
     require!(b > 0, MainErrorCode::InvalidLiquidityParameter);
-    require!((2..=10).contains(&outcome_count), MainErrorCode::InvalidOutcomeCount);
+    require!((2..=MAX_OUTCOMES).contains(&(outcome_count as usize)), MainErrorCode::InvalidOutcomeCount);
+    require!(q.len() >= outcome_count as usize, MainErrorCode::InvalidOutcomeCount);
 
     let b_u128 = b as u128;
 
     // r_i = (q_i / b) scaled => q_i * SCALE / b
-    let mut r: [u128; 10] = [0u128; 10];
+    let mut r: Vec<u128> = vec![0u128; outcome_count as usize];
     let mut max_r: u128 = 0;
 
     for i in 0..(outcome_count as usize) {
@@ -176,8 +178,8 @@ pub fn lmsr_cost(q: &[u64; 10], b: u64, outcome_count: u8) -> Result<u64> {
 
     // sum exp(r_i - max_r)
     let mut exp_sum: u128 = 0;
-    for i in 0..(outcome_count as usize) {
-        let diff = (r[i] as i128)
+    for ri in r.iter() {
+        let diff = (*ri as i128)
             .checked_sub(max_r as i128)
             .ok_or(MainErrorCode::MathOverflow)?;
         // diff <= 0, safe
@@ -201,7 +203,7 @@ pub fn lmsr_cost(q: &[u64; 10], b: u64, outcome_count: u8) -> Result<u64> {
 
 /// Cost of buying Δ shares on outcome i: C(q+Δ) - C(q)
 pub fn lmsr_buy_cost(
-    q: &[u64; 10],
+    q: &[u64],
     b: u64,
     outcome_index: u8,
     amount: u64,
@@ -215,7 +217,7 @@ pub fn lmsr_buy_cost(
 
     let cost_before = lmsr_cost(q, b, outcome_count)?;
 
-    let mut q_after = *q;
+    let mut q_after = q.to_vec();
     q_after[outcome_index as usize] = q_after[outcome_index as usize]
         .checked_add(amount)
         .ok_or(MainErrorCode::MathOverflow)?;
@@ -231,7 +233,7 @@ pub fn lmsr_buy_cost(
 
 /// Refund from selling Δ shares on outcome i: C(q) - C(q-Δ)
 pub fn lmsr_sell_refund(
-    q: &[u64; 10],
+    q: &[u64],
     b: u64,
     outcome_index: u8,
     amount: u64,
@@ -249,7 +251,7 @@ pub fn lmsr_sell_refund(
 
     let cost_before = lmsr_cost(q, b, outcome_count)?;
 
-    let mut q_after = *q;
+    let mut q_after = q.to_vec();
     q_after[outcome_index as usize] = q_after[outcome_index as usize]
         .checked_sub(amount)
         .ok_or(MainErrorCode::InsufficientShares)?;
@@ -262,18 +264,24 @@ pub fn lmsr_sell_refund(
 }
 
 /// Price p_i = exp(q_i/b) / sum_j exp(q_j/b), scaled by SCALE (1e9)
-pub fn lmsr_price(q: &[u64; 10], b: u64, outcome_index: u8, outcome_count: u8) -> Result<u64> {
-    require!(b > 0, MainErrorCode::InvalidLiquidityParameter);
-    require!((2..=10).contains(&outcome_count), MainErrorCode::InvalidOutcomeCount);
+pub fn lmsr_price(q: &[u64], b: u64, outcome_index: u8, outcome_count: u8) -> Result<u64> {
     require!(
         (outcome_index as usize) < (outcome_count as usize),
         MainErrorCode::InvalidOutcomeCount
     );
+    Ok(lmsr_prices(q, b, outcome_count)?[outcome_index as usize])
+}
+
+/// Every outcome's price from one softmax pass, scaled by SCALE.
+pub fn lmsr_prices(q: &[u64], b: u64, outcome_count: u8) -> Result<Vec<u64>> {
+    require!(b > 0, MainErrorCode::InvalidLiquidityParameter);
+    require!((2..=MAX_OUTCOMES).contains(&(outcome_count as usize)), MainErrorCode::InvalidOutcomeCount);
+    require!(q.len() >= outcome_count as usize, MainErrorCode::InvalidOutcomeCount);
 
     let b_u128 = b as u128;
 
     // compute r_i and max
-    let mut r: [u128; 10] = [0u128; 10];
+    let mut r: Vec<u128> = vec![0u128; outcome_count as usize];
     let mut max_r: u128 = 0;
     for i in 0..(outcome_count as usize) {
         let ri = (q[i] as u128)
@@ -285,26 +293,380 @@ pub fn lmsr_price(q: &[u64; 10], b: u64, outcome_index: u8, outcome_count: u8) -
         }
     }
 
-    // denom = sum exp(r_i-max_r), numer = exp(r_k-max_r)
+    // denom = sum exp(r_i-max_r), numer_k = exp(r_k-max_r)
     let mut denom: u128 = 0;
-    let mut numer: u128 = 0;
+    let mut numers: Vec<u128> = Vec::with_capacity(r.len());
 
-    for i in 0..(outcome_count as usize) {
-        let diff = (r[i] as i128)
+    for ri in r.iter() {
+        let diff = (*ri as i128)
             .checked_sub(max_r as i128)
             .ok_or(MainErrorCode::MathOverflow)?;
         let e = exp_fixed_signed(diff)?;
         denom = denom.checked_add(e).ok_or(MainErrorCode::MathOverflow)?;
-        if i == outcome_index as usize {
-            numer = e;
-        }
+        numers.push(e);
     }
 
     require!(denom > 0, MainErrorCode::MathOverflow);
 
-    let price = numer
+    numers
+        .iter()
+        .map(|numer| {
+            let price = numer
+                .checked_mul(SCALE).ok_or(MainErrorCode::MathOverflow)?
+                .checked_div(denom).ok_or(MainErrorCode::MathOverflow)?;
+            Ok(u64::try_from(price).map_err(|_| MainErrorCode::MathOverflow)?)
+        })
+        .collect()
+}
+/* ============================== LS-LMSR ============================== */
+
+/// Basis points denominator for the LS-LMSR vig
+const BPS: u128 = 10_000;
+
+/// exp(x) for x <= 0 (scaled); terms below -MAX_EXP_INPUT are negligible and read as 0.
+/// LS-LMSR exponents reach 1/alpha, which can exceed the series range.
+fn exp_fixed_nonpos(x: i128) -> Result<u128> {
+    if x < -(MAX_EXP_INPUT as i128) {
+        return Ok(0);
+    }
+    exp_fixed_signed(x)
+}
+
+/// alpha (scaled) for a target vig: prices sum to at most 1 + vig,
+/// and alpha = vig / (n * ln n).
+pub fn ls_lmsr_alpha(vig_bps: u16, outcome_count: u8) -> Result<u128> {
+    require!(vig_bps > 0, MainErrorCode::InvalidLiquidityParameter);
+    require!((2..=MAX_OUTCOMES).contains(&(outcome_count as usize)), MainErrorCode::InvalidOutcomeCount);
+
+    let n = outcome_count as u128;
+    let ln_n = ln_fixed(n.checked_mul(SCALE).ok_or(MainErrorCode::MathOverflow)?)?;
+
+    let num = (vig_bps as u128)
         .checked_mul(SCALE).ok_or(MainErrorCode::MathOverflow)?
-        .checked_div(denom).ok_or(MainErrorCode::MathOverflow)?;
+        .checked_mul(SCALE).ok_or(MainErrorCode::MathOverflow)?;
+    let den = BPS
+        .checked_mul(n).ok_or(MainErrorCode::MathOverflow)?
+        .checked_mul(ln_n).ok_or(MainErrorCode::MathOverflow)?;
+
+    let alpha = num.checked_div(den).ok_or(MainErrorCode::MathOverflow)?;
+    require!(alpha > 0, MainErrorCode::InvalidLiquidityParameter);
+    Ok(alpha)
+}
+
+/// Shared LS-LMSR terms for q:
+/// (sum q, b, max r, sum exp(r_i - max r), exp(r_i - max r) per outcome)
+struct LsTerms {
+    total_q: u128,
+    b: u128,
+    max_r: u128,
+    exp_sum: u128,
+    exps: Vec<u128>,
+}
+
+fn ls_terms(q: &[u64], alpha: u128, outcome_count: u8) -> Result<LsTerms> {
+    require!(alpha > 0, MainErrorCode::InvalidLiquidityParameter);
+    require!((2..=MAX_OUTCOMES).contains(&(outcome_count as usize)), MainErrorCode::InvalidOutcomeCount);
+    require!(q.len() >= outcome_count as usize, MainErrorCode::InvalidOutcomeCount);
+
+    let q = &q[..outcome_count as usize];
+
+    let mut total_q: u128 = 0;
+    for q_i in q.iter() {
+        total_q = total_q.checked_add(*q_i as u128).ok_or(MainErrorCode::MathOverflow)?;
+    }
+
+    // b = alpha * sum(q)
+    let b = total_q
+        .checked_mul(alpha).ok_or(MainErrorCode::MathOverflow)?
+        .checked_div(SCALE).ok_or(MainErrorCode::MathOverflow)?;
+    require!(b > 0, MainErrorCode::InvalidLiquidityParameter);
+
+    let mut r: Vec<u128> = Vec::with_capacity(q.len());
+    let mut max_r: u128 = 0;
+    for q_i in q.iter() {
+        let ri = (*q_i as u128)
+            .checked_mul(SCALE).ok_or(MainErrorCode::MathOverflow)?
+            .checked_div(b).ok_or(MainErrorCode::MathOverflow)?;
+        max_r = max_r.max(ri);
+        r.push(ri);
+    }
+
+    let mut exps: Vec<u128> = Vec::with_capacity(q.len());
+    let mut exp_sum: u128 = 0;
+    for ri in r.iter() {
+        let diff = (*ri as i128)
+            .checked_sub(max_r as i128)
+            .ok_or(MainErrorCode::MathOverflow)?;
+        let e = exp_fixed_nonpos(diff)?;
+        exp_sum = exp_sum.checked_add(e).ok_or(MainErrorCode::MathOverflow)?;
+        exps.push(e);
+    }
 
-    Ok(u64::try_from(price).map_err(|_| MainErrorCode::MathOverflow)?)
-}
\ No newline at end of file
+    require!(exp_sum >= SCALE, MainErrorCode::MathOverflow);
+
+    Ok(LsTerms { total_q, b, max_r, exp_sum, exps })
+}
+
+/// C(q) = b(q) * ln(sum_i exp(q_i / b(q))), b(q) = alpha * sum(q)
+/// q in lamports units, alpha scaled by SCALE => cost in lamports. C(0) = 0.
+pub fn ls_lmsr_cost(q: &[u64], alpha: u128, outcome_count: u8) -> Result<u64> {
+    if q.iter().take(outcome_count as usize).all(|q_i| *q_i == 0) {
+        return Ok(0);
+    }
+
+    let t = ls_terms(q, alpha, outcome_count)?;
+
+    let ln_total = t
+        .max_r
+        .checked_add(ln_fixed(t.exp_sum)?)
+        .ok_or(MainErrorCode::MathOverflow)?;
+
+    // cost = b * ln_total / SCALE, ceil
+    let cost_u128 = t.b.checked_mul(ln_total).ok_or(MainErrorCode::MathOverflow)?;
+    let cost_u128 = div_ceil_u128(cost_u128, SCALE)?;
+
+    Ok(u64::try_from(cost_u128).map_err(|_| MainErrorCode::MathOverflow)?)
+}
+
+/// Cost of buying Δ on outcome i: C(q+Δ) - C(q)
+pub fn ls_lmsr_buy_cost(
+    q: &[u64],
+    alpha: u128,
+    outcome_index: u8,
+    amount: u64,
+    outcome_count: u8,
+) -> Result<u64> {
+    require!(
+        (outcome_index as usize) < (outcome_count as usize),
+        MainErrorCode::InvalidOutcomeCount
+    );
+    require!(amount > 0, MainErrorCode::InvalidShares);
+
+    let cost_before = ls_lmsr_cost(q, alpha, outcome_count)?;
+
+    let mut q_after = q.to_vec();
+    q_after[outcome_index as usize] = q_after[outcome_index as usize]
+        .checked_add(amount)
+        .ok_or(MainErrorCode::MathOverflow)?;
+
+    let cost_after = ls_lmsr_cost(&q_after, alpha, outcome_count)?;
+
+    let delta = cost_after
+        .checked_sub(cost_before)
+        .ok_or(MainErrorCode::MathOverflow)?;
+
+    Ok(delta.max(1))
+}
+
+/// Refund from selling Δ on outcome i: C(q) - C(q-Δ)
+pub fn ls_lmsr_sell_refund(
+    q: &[u64],
+    alpha: u128,
+    outcome_index: u8,
+    amount: u64,
+    outcome_count: u8,
+) -> Result<u64> {
+    require!(
+        (outcome_index as usize) < (outcome_count as usize),
+        MainErrorCode::InvalidOutcomeCount
+    );
+    require!(amount > 0, MainErrorCode::InvalidShares);
+    require!(
+        q[outcome_index as usize] >= amount,
+        MainErrorCode::InsufficientShares
+    );
+
+    let cost_before = ls_lmsr_cost(q, alpha, outcome_count)?;
+
+    let mut q_after = q.to_vec();
+    q_after[outcome_index as usize] = q_after[outcome_index as usize]
+        .checked_sub(amount)
+        .ok_or(MainErrorCode::InsufficientShares)?;
+
+    let cost_after = ls_lmsr_cost(&q_after, alpha, outcome_count)?;
+
+    Ok(cost_before.saturating_sub(cost_after))
+}
+
+/// Marginal price of outcome i, scaled by SCALE. Prices sum to >= 1 (the vig).
+/// p_i = alpha * ln(sum_j e_j) + (sum_j q_j * (e_i - e_j)) / (sum_j q_j * sum_j e_j)
+pub fn ls_lmsr_price(q: &[u64], alpha: u128, outcome_index: u8, outcome_count: u8) -> Result<u64> {
+    require!(
+        (outcome_index as usize) < (outcome_count as usize),
+        MainErrorCode::InvalidOutcomeCount
+    );
+    Ok(ls_lmsr_prices(q, alpha, outcome_count)?[outcome_index as usize])
+}
+
+/// Every outcome's marginal price from one set of LS-LMSR terms, scaled by SCALE.
+pub fn ls_lmsr_prices(q: &[u64], alpha: u128, outcome_count: u8) -> Result<Vec<u64>> {
+    let t = ls_terms(q, alpha, outcome_count)?;
+
+    let ln_total = t
+        .max_r
+        .checked_add(ln_fixed(t.exp_sum)?)
+        .ok_or(MainErrorCode::MathOverflow)?;
+    let entropy_term = alpha
+        .checked_mul(ln_total).ok_or(MainErrorCode::MathOverflow)?
+        .checked_div(SCALE).ok_or(MainErrorCode::MathOverflow)?;
+
+    // q-weighted mean of e_j (scaled, <= SCALE)
+    let mut weighted: u128 = 0;
+    for (q_j, e_j) in q.iter().zip(t.exps.iter()) {
+        weighted = weighted
+            .checked_add((*q_j as u128).checked_mul(*e_j).ok_or(MainErrorCode::MathOverflow)?)
+            .ok_or(MainErrorCode::MathOverflow)?;
+    }
+    let mean_e = weighted.checked_div(t.total_q).ok_or(MainErrorCode::MathOverflow)?;
+
+    t.exps
+        .iter()
+        .map(|e_i| {
+            let diff = (*e_i as i128)
+                .checked_sub(mean_e as i128)
+                .ok_or(MainErrorCode::MathOverflow)?;
+            let softmax_term = diff
+                .checked_mul(SCALE as i128).ok_or(MainErrorCode::MathOverflow)?
+                .checked_div(t.exp_sum as i128).ok_or(MainErrorCode::MathOverflow)?;
+
+            let price = (entropy_term as i128)
+                .checked_add(softmax_term)
+                .ok_or(MainErrorCode::MathOverflow)?
+                .max(0);
+
+            Ok(u64::try_from(price).map_err(|_| MainErrorCode::MathOverflow)?)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VIG_BPS: u16 = 500;
+    const SEED: u64 = 1_000_000_000; // 1 SOL of inventory per outcome
+
+    fn seeded(n: u8) -> Vec<u64> {
+        vec![SEED; n as usize]
+    }
+
+    #[test]
+    fn ls_prices_sum_within_vig() {
+        for n in [2u8, 3, 10, 64] {
+            let alpha = ls_lmsr_alpha(VIG_BPS, n).unwrap();
+            let mut q = seeded(n);
+            q[0] += 3 * SEED;
+
+            let sum: u64 = (0..n).map(|i| ls_lmsr_price(&q, alpha, i, n).unwrap()).sum();
+            let one = SCALE as u64;
+            assert!(sum >= one - 1_000, "n={n} sum={sum}");
+            assert!(sum <= one + one * VIG_BPS as u64 / 10_000 + 1_000, "n={n} sum={sum}");
+        }
+    }
+
+    #[test]
+    fn ls_round_trip_is_not_profitable() {
+        let n = 3;
+        let alpha = ls_lmsr_alpha(VIG_BPS, n).unwrap();
+        let mut q = seeded(n);
+
+        for amount in [1_000u64, 10_000_000, SEED, 5 * SEED] {
+            let cost = ls_lmsr_buy_cost(&q, alpha, 1, amount, n).unwrap();
+            q[1] += amount;
+            let refund = ls_lmsr_sell_refund(&q, alpha, 1, amount, n).unwrap();
+            q[1] -= amount;
+            assert!(refund <= cost, "amount={amount} cost={cost} refund={refund}");
+        }
+    }
+
+    #[test]
+    fn ls_complete_set_costs_at_least_its_payout() {
+        // buying one of every outcome pays exactly `amount` at resolution
+        let n = 4;
+        let alpha = ls_lmsr_alpha(VIG_BPS, n).unwrap();
+        let mut q = seeded(n);
+        q[2] += 7 * SEED;
+
+        let amount = SEED / 2;
+        let before = ls_lmsr_cost(&q, alpha, n).unwrap();
+        let after_q: Vec<u64> = q.iter().map(|q_i| q_i + amount).collect();
+        let after = ls_lmsr_cost(&after_q, alpha, n).unwrap();
+        assert!(after - before >= amount);
+    }
+
+    #[test]
+    fn ls_splitting_a_buy_does_not_save() {
+        let n = 2;
+        let alpha = ls_lmsr_alpha(VIG_BPS, n).unwrap();
+        let q = seeded(n);
+
+        let whole = ls_lmsr_buy_cost(&q, alpha, 0, 2 * SEED, n).unwrap();
+
+        let mut split_q = q.clone();
+        let mut split = 0u64;
+        for _ in 0..8 {
+            split += ls_lmsr_buy_cost(&split_q, alpha, 0, SEED / 4, n).unwrap();
+            split_q[0] += SEED / 4;
+        }
+        // path independent up to per-call rounding
+        assert!(split + 8 >= whole, "split={split} whole={whole}");
+    }
+
+    #[test]
+    fn ls_cost_covers_max_payout() {
+        // C(q) >= max q_i: collected cost always covers the largest liability
+        let n = 5;
+        let alpha = ls_lmsr_alpha(VIG_BPS, n).unwrap();
+        let mut q = seeded(n);
+        for (i, extra) in [0u64, 3, 11, 1, 40].iter().enumerate() {
+            q[i] += extra * SEED;
+            let cost = ls_lmsr_cost(&q, alpha, n).unwrap();
+            assert!(cost >= *q.iter().max().unwrap());
+        }
+    }
+
+    #[test]
+    fn ls_loss_bounded_by_seed_vig() {
+        // the maker holds the seed; worst-case loss <= C(q0) - seed = vig * seed
+        for n in [2u8, 8, 64] {
+            let alpha = ls_lmsr_alpha(VIG_BPS, n).unwrap();
+            let q0 = seeded(n);
+            let bound = ls_lmsr_cost(&q0, alpha, n).unwrap() - SEED;
+            assert!(bound <= SEED * VIG_BPS as u64 / 10_000 + 1_000, "n={n} bound={bound}");
+
+            let mut q = q0.clone();
+            let mut collected = 0u64;
+            for step in 0..20u64 {
+                let amount = SEED * (step + 1) / 2;
+                collected += ls_lmsr_buy_cost(&q, alpha, 0, amount, n).unwrap();
+                q[0] += amount;
+
+                let liability = q[0] - q0[0];
+                let loss = liability.saturating_sub(collected);
+                // one lamport of rounding per call
+                assert!(loss <= bound + step + 1, "n={n} loss={loss} bound={bound}");
+            }
+        }
+    }
+
+    #[test]
+    fn ls_depth_grows_with_volume() {
+        let n = 2;
+        let alpha = ls_lmsr_alpha(VIG_BPS, n).unwrap();
+        let thin = seeded(n);
+        let deep: Vec<u64> = thin.iter().map(|q_i| q_i * 10).collect();
+
+        let amount = SEED / 10;
+        let p_thin = ls_lmsr_price(&thin, alpha, 0, n).unwrap();
+        let p_deep = ls_lmsr_price(&deep, alpha, 0, n).unwrap();
+
+        let mut thin_after = thin.clone();
+        thin_after[0] += amount;
+        let mut deep_after = deep.clone();
+        deep_after[0] += amount;
+
+        let impact_thin = ls_lmsr_price(&thin_after, alpha, 0, n).unwrap() - p_thin;
+        let impact_deep = ls_lmsr_price(&deep_after, alpha, 0, n).unwrap() - p_deep;
+        assert!(impact_deep < impact_thin);
+    }
+}